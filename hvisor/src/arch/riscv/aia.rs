@@ -0,0 +1,410 @@
+//! RISC-V AIA backend: per-zone APLIC emulation and IMSIC guest interrupt files.
+//!
+//! The host S-level APLIC runs in MSI delivery mode. The sources owned by a zone are
+//! routed to the guest interrupt file `IMSIC_VGEIN` of the physical hart backing the
+//! target vcpu, and the guest IMSIC pages are mapped onto those files, so MSIs reach
+//! the guest without hypervisor exits.
+use super::cpu::ArchCpu;
+use super::irqchip::is_compatible;
+use super::plic::{plic_contexts, IRQ_S_EXT};
+use crate::arch::riscv::csr::*;
+use crate::error::HvResult;
+use crate::memory::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion};
+use crate::mmio::MmioDevice;
+use crate::zone::Zone;
+use alloc::vec::Vec;
+use spin::Once;
+// APLIC Memory Map
+//  base + 0x0000: domaincfg
+//  base + 0x0004: sourcecfg[1] ... base + 0x0FFC: sourcecfg[1023]
+//  base + 0x1BC0: mmsiaddrcfg/mmsiaddrcfgh/smsiaddrcfg/smsiaddrcfgh (root domain only)
+//  base + 0x1C00: setip[0~31],    base + 0x1CDC: setipnum
+//  base + 0x1D00: in_clrip[0~31], base + 0x1DDC: clripnum
+//  base + 0x1E00: setie[0~31],    base + 0x1EDC: setienum
+//  base + 0x1F00: clrie[0~31],    base + 0x1FDC: clrienum
+//  base + 0x2000: setipnum_le,    base + 0x2004: setipnum_be
+//  base + 0x3000: genmsi
+//  base + 0x3004: target[1] ... base + 0x3FFC: target[1023]
+pub const APLIC_DOMAINCFG: usize = 0x0000;
+pub const APLIC_SOURCECFG_BASE: usize = 0x0000;
+pub const APLIC_MSIADDRCFG_BASE: usize = 0x1BC0;
+pub const APLIC_SETIP_BASE: usize = 0x1C00;
+pub const APLIC_SETIPNUM: usize = 0x1CDC;
+pub const APLIC_CLRIP_BASE: usize = 0x1D00;
+pub const APLIC_CLRIPNUM: usize = 0x1DDC;
+pub const APLIC_SETIE_BASE: usize = 0x1E00;
+pub const APLIC_SETIENUM: usize = 0x1EDC;
+pub const APLIC_CLRIE_BASE: usize = 0x1F00;
+pub const APLIC_CLRIENUM: usize = 0x1FDC;
+pub const APLIC_SETIPNUM_LE: usize = 0x2000;
+pub const APLIC_SETIPNUM_BE: usize = 0x2004;
+pub const APLIC_GENMSI: usize = 0x3000;
+pub const APLIC_TARGET_BASE: usize = 0x3000;
+pub const APLIC_SIZE: usize = 0x4000;
+pub const APLIC_MAX_IRQ: usize = 1023;
+/// domaincfg: interrupt enable, MSI delivery mode, read-only top byte
+pub const APLIC_DOMAINCFG_IE: u32 = 1 << 8;
+pub const APLIC_DOMAINCFG_DM: u32 = 1 << 2;
+const APLIC_DOMAINCFG_RO80: u32 = 0x80 << 24;
+/// sourcecfg: delegate bit, a guest domain has no child domain
+const APLIC_SOURCECFG_D: u32 = 1 << 10;
+/// target in MSI mode: hart index, guest index and external interrupt identity
+const APLIC_TARGET_HART_SHIFT: u32 = 18;
+const APLIC_TARGET_GUEST_SHIFT: u32 = 12;
+const APLIC_TARGET_EIID_MASK: u32 = 0x7ff;
+/// genmsi busy bit
+const APLIC_GENMSI_BUSY: u32 = 1 << 12;
+pub const IMSIC_FILE_SIZE: usize = 0x1000;
+/// guest interrupt file given to the vcpu of each hart, one zone vcpu runs on a hart
+pub const IMSIC_VGEIN: usize = 1;
+
+pub static AIA: Once<Aia> = Once::new();
+
+pub fn host_aia<'a>() -> Option<&'a Aia> {
+    AIA.get()
+}
+/// probe the host S-level APLIC domain and IMSIC from the host device tree
+pub fn init_aia(host_fdt: &fdt::Fdt) -> bool {
+    // the S-level domain is the leaf one, the root domain delegates to it
+    let aplic = host_fdt.all_nodes().find(|node| {
+        is_compatible(node, &["riscv,aplic"]) && node.property("riscv,children").is_none()
+    });
+    let imsic = host_fdt.all_nodes().find(|node| {
+        is_compatible(node, &["riscv,imsics"])
+            && plic_contexts(host_fdt, node)
+                .iter()
+                .all(|&(_, irq)| irq == IRQ_S_EXT)
+    });
+    let (aplic, imsic) = match (aplic, imsic) {
+        (Some(aplic), Some(imsic)) => (aplic, imsic),
+        _ => return false,
+    };
+    let guest_index_bits = imsic
+        .property("riscv,guest-index-bits")
+        .and_then(|p| p.as_usize())
+        .unwrap_or(0);
+    if guest_index_bits == 0 {
+        warn!("host IMSIC has no guest interrupt file, AIA is not used for guests");
+        return false;
+    }
+    let aplic_reg = aplic.reg().unwrap().next().unwrap();
+    let imsic_reg = imsic.reg().unwrap().next().unwrap();
+    let aia = Aia {
+        aplic: Aplic {
+            base: aplic_reg.starting_address as usize,
+            num_sources: aplic
+                .property("riscv,num-sources")
+                .and_then(|p| p.as_usize())
+                .unwrap_or(APLIC_MAX_IRQ),
+        },
+        imsic: Imsic {
+            base: imsic_reg.starting_address as usize,
+            hart_stride: IMSIC_FILE_SIZE << guest_index_bits,
+            harts: plic_contexts(host_fdt, &imsic)
+                .into_iter()
+                .map(|(hart, _)| hart)
+                .collect(),
+        },
+    };
+    info!(
+        "AIA: aplic@{:#x} {} sources, imsic@{:#x} stride {:#x}",
+        aia.aplic.base, aia.aplic.num_sources, aia.imsic.base, aia.imsic.hart_stride
+    );
+    AIA.call_once(|| aia);
+    true
+}
+
+pub struct Aia {
+    pub aplic: Aplic,
+    pub imsic: Imsic,
+}
+/// S-level APLIC domain of the host, in MSI delivery mode
+pub struct Aplic {
+    pub base: usize,
+    pub num_sources: usize,
+}
+impl Aplic {
+    pub fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+    pub fn write(&self, offset: usize, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + offset) as *mut u32, value);
+        }
+    }
+}
+/// S-level IMSIC of the host, each hart has its S file followed by its guest files
+pub struct Imsic {
+    pub base: HostPhysAddr,
+    pub hart_stride: usize,
+    /// hart of each IMSIC hart index
+    pub harts: Vec<usize>,
+}
+impl Imsic {
+    pub fn hart_index(&self, hart: usize) -> Option<usize> {
+        self.harts.iter().position(|&h| h == hart)
+    }
+    /// guest interrupt file `vgein` of `hart`
+    pub fn guest_file(&self, hart: usize, vgein: usize) -> Option<HostPhysAddr> {
+        let index = self.hart_index(hart)?;
+        Some(self.base + index * self.hart_stride + vgein * IMSIC_FILE_SIZE)
+    }
+}
+
+/// virtual APLIC domain of a zone, in MSI delivery mode. The guest view is kept in
+/// software, only the sources owned by the zone reach the host APLIC.
+pub struct VirtAplic {
+    num_sources: usize,
+    /// bitmap of the sources owned by the zone
+    owned: Vec<u32>,
+    domaincfg: u32,
+    sourcecfg: Vec<u32>,
+    /// target registers as written by the guest, with guest hart indices
+    target: Vec<u32>,
+    genmsi: u32,
+    /// physical hart of each guest hart index, the index of its guest IMSIC page; None
+    /// for an index the zone can not target
+    harts: Vec<Option<usize>>,
+}
+impl VirtAplic {
+    pub fn new(num_sources: usize) -> Self {
+        Self {
+            num_sources,
+            owned: vec![0; num_sources / 32 + 1],
+            domaincfg: 0,
+            sourcecfg: vec![0; num_sources + 1],
+            target: vec![0; num_sources + 1],
+            genmsi: 0,
+            harts: Vec::new(),
+        }
+    }
+    /// build the vaplic of a zone from the sources used in the guest device tree
+    pub fn from_fdt(fdt: &fdt::Fdt, extra_irqs: &[u32]) -> Self {
+        let num_sources = fdt
+            .find_node("/soc/aplic")
+            .and_then(|aplic| aplic.property("riscv,num-sources"))
+            .and_then(|p| p.as_usize())
+            .unwrap_or(APLIC_MAX_IRQ);
+        let mut vaplic = Self::new(num_sources);
+        // #interrupt-cells of the aplic is 2: source and trigger type
+        super::plic::fdt_irqs(fdt, 2)
+            .into_iter()
+            .chain(extra_irqs.iter().copied())
+            .for_each(|irq| vaplic.add_source(irq));
+        vaplic
+    }
+    pub fn add_source(&mut self, irq: u32) {
+        let irq = irq as usize;
+        if irq == 0 || irq > self.num_sources {
+            warn!("irq {} out of the vaplic sources", irq);
+            return;
+        }
+        self.owned[irq / 32] |= 1 << (irq % 32);
+    }
+    pub fn owns(&self, irq: usize) -> bool {
+        irq <= self.num_sources && self.owned[irq / 32] & (1 << (irq % 32)) != 0
+    }
+    fn owned_word(&self, word: usize) -> u32 {
+        self.owned.get(word).copied().unwrap_or(0)
+    }
+    pub fn owned_irqs(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..=self.num_sources).filter(move |&irq| self.owns(irq))
+    }
+    /// forget the guest state and turn the owned host sources off, used on zone shutdown
+    /// and reboot
+    pub fn reset(&mut self, aplic: &Aplic) {
+        self.owned_irqs().for_each(|irq| {
+            aplic.write(APLIC_CLRIENUM, irq as u32);
+            aplic.write(APLIC_SOURCECFG_BASE + irq * 4, 0);
+        });
+        self.domaincfg = 0;
+        self.sourcecfg.fill(0);
+        self.target.fill(0);
+        self.genmsi = 0;
+    }
+    /// turn a guest target (guest hart index, guest index 0, eiid) into the host one
+    /// (physical hart index, `IMSIC_VGEIN`, eiid), None for a hart index outside the zone
+    fn host_target(&self, imsic: &Imsic, value: u32) -> Option<u32> {
+        let hart_index = (value >> APLIC_TARGET_HART_SHIFT) as usize;
+        let phart = self.harts.get(hart_index).copied().flatten()?;
+        let index = imsic.hart_index(phart)?;
+        Some(
+            (index as u32) << APLIC_TARGET_HART_SHIFT
+                | (IMSIC_VGEIN as u32) << APLIC_TARGET_GUEST_SHIFT
+                | value & APLIC_TARGET_EIID_MASK,
+        )
+    }
+    /// emulate a guest access to its APLIC domain, returns the value of a read
+    pub fn access(&mut self, zone: &Zone, aia: &Aia, offset: usize, write: Option<u32>) -> u32 {
+        let aplic = &aia.aplic;
+        match (offset, write) {
+            (APLIC_DOMAINCFG, None) => APLIC_DOMAINCFG_RO80 | self.domaincfg,
+            (APLIC_DOMAINCFG, Some(value)) => {
+                // only MSI delivery mode is offered
+                self.domaincfg = value & APLIC_DOMAINCFG_IE | APLIC_DOMAINCFG_DM;
+                0
+            }
+            (0x0004..=0x0FFC, _) => {
+                let irq = offset / 4;
+                if !self.owns(irq) {
+                    return 0;
+                }
+                match write {
+                    None => self.sourcecfg[irq],
+                    Some(value) => {
+                        let value = if value & APLIC_SOURCECFG_D != 0 {
+                            0
+                        } else {
+                            value & 0x7
+                        };
+                        self.sourcecfg[irq] = value;
+                        aplic.write(APLIC_SOURCECFG_BASE + irq * 4, value);
+                        0
+                    }
+                }
+            }
+            // the msi address of a non-root domain is not programmable
+            (APLIC_MSIADDRCFG_BASE..=0x1BCC, _) => 0,
+            (APLIC_SETIP_BASE..=0x1C7C, _)
+            | (APLIC_CLRIP_BASE..=0x1D7C, _)
+            | (APLIC_SETIE_BASE..=0x1E7C, _)
+            | (APLIC_CLRIE_BASE..=0x1F7C, _) => {
+                let owned = self.owned_word((offset & 0x7f) / 4);
+                match write {
+                    None => aplic.read(offset) & owned,
+                    Some(value) => {
+                        aplic.write(offset, value & owned);
+                        0
+                    }
+                }
+            }
+            (
+                APLIC_SETIPNUM | APLIC_CLRIPNUM | APLIC_SETIENUM | APLIC_CLRIENUM
+                | APLIC_SETIPNUM_LE,
+                Some(irq),
+            ) => {
+                if self.owns(irq as usize) {
+                    aplic.write(offset, irq);
+                }
+                0
+            }
+            (APLIC_SETIPNUM_BE, Some(irq)) => {
+                if self.owns(irq.swap_bytes() as usize) {
+                    aplic.write(offset, irq);
+                }
+                0
+            }
+            (APLIC_GENMSI, None) => self.genmsi,
+            (APLIC_GENMSI, Some(value)) => {
+                self.genmsi = value & !APLIC_GENMSI_BUSY;
+                if let Some(target) = self.host_target(&aia.imsic, value) {
+                    aplic.write(APLIC_GENMSI, target);
+                }
+                0
+            }
+            (0x3004..=0x3FFC, _) => {
+                let irq = (offset - APLIC_TARGET_BASE) / 4;
+                if !self.owns(irq) {
+                    return 0;
+                }
+                match write {
+                    None => self.target[irq],
+                    Some(value) => match self.host_target(&aia.imsic, value) {
+                        Some(target) => {
+                            self.target[irq] = value;
+                            aplic.write(APLIC_TARGET_BASE + irq * 4, target);
+                            0
+                        }
+                        None => {
+                            warn!("zone {} target irq {} to a foreign hart", zone.vmid, irq);
+                            0
+                        }
+                    },
+                }
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// map the guest IMSIC page of each guest hart onto the guest interrupt file of the
+/// physical hart backing it, the vaplic targets the harts by the same index
+pub fn map_guest_imsic(zone: &mut Zone, guest_fdt: &fdt::Fdt) -> HvResult {
+    let aia = match host_aia() {
+        Some(aia) => aia,
+        None => return Ok(()),
+    };
+    let imsic = match guest_fdt.find_node("/soc/imsics") {
+        Some(imsic) => imsic,
+        None => return Ok(()),
+    };
+    let gbase = imsic.reg().unwrap().next().unwrap().starting_address as GuestPhysAddr;
+    // the guest sees one S file per hart, in the order of its interrupts-extended
+    let harts: Vec<Option<usize>> = plic_contexts(guest_fdt, &imsic)
+        .into_iter()
+        .map(|(vhart, _)| zone.phart_of(vhart))
+        .collect();
+    for (index, &phart) in harts.iter().enumerate() {
+        let file = phart.and_then(|phart| aia.imsic.guest_file(phart, IMSIC_VGEIN));
+        let file = match file {
+            Some(file) => file,
+            None => {
+                warn!(
+                    "zone {} imsic of hart index {} has no guest file",
+                    zone.vmid, index
+                );
+                continue;
+            }
+        };
+        let gpa = gbase + index * IMSIC_FILE_SIZE;
+        info!("map guest imsic {:#x} -> guest file {:#x}", gpa, file);
+        zone.gpm.insert(MemoryRegion::new_with_offset_mapper(
+            gpa,
+            file,
+            IMSIC_FILE_SIZE,
+            MemFlags::READ | MemFlags::WRITE,
+        ))?;
+    }
+    zone.vaplic.get_mut().harts = harts;
+    Ok(())
+}
+
+/// vAPLIC of the zone running on the current hart, registered in the zone MMIO regions
+/// at the guest aplic address. Only 32-bit accesses are allowed.
+pub struct VirtAplicDevice;
+impl MmioDevice for VirtAplicDevice {
+    fn read(
+        &self,
+        _current_cpu: &mut ArchCpu,
+        zone: &Zone,
+        offset: usize,
+        width: usize,
+    ) -> HvResult<usize> {
+        if width != 4 {
+            return hv_result_err!(EINVAL, format!("APLIC read of width {}", width));
+        }
+        let value = zone
+            .vaplic
+            .lock()
+            .access(zone, host_aia().unwrap(), offset, None);
+        trace!("APLIC read offset@{:#x} -> {:#x}", offset, value);
+        Ok(value as usize)
+    }
+    fn write(
+        &self,
+        _current_cpu: &mut ArchCpu,
+        zone: &Zone,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> HvResult {
+        if width != 4 {
+            return hv_result_err!(EINVAL, format!("APLIC write of width {}", width));
+        }
+        zone.vaplic
+            .lock()
+            .access(zone, host_aia().unwrap(), offset, Some(value as u32));
+        trace!("APLIC write offset@{:#x} <- {:#x}", offset, value);
+        Ok(())
+    }
+}
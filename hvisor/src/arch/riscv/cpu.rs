@@ -1,8 +1,13 @@
 #![allow(dead_code)]
 
 use crate::arch::riscv::csr::*;
+use crate::arch::riscv::irqchip::IrqSet;
+use crate::arch::riscv::pmu::Vpmu;
+use crate::arch::riscv::sbi::SBI_HSM_STATE;
 use crate::consts::{INVALID_ADDRESS, PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
 use crate::memory::addr::VirtAddr;
+use crate::percpu::get_cpu_data;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use riscv::register::sie;
 #[repr(C)]
 #[derive(Debug)]
@@ -18,6 +23,33 @@ pub struct ArchCpu {
     pub ssi: usize,
     pub sti: usize,
     pub sei: usize,
+    pub hsm_state: AtomicUsize,
+    pub start_addr: usize,
+    pub start_opaque: usize,
+    /// host physical address of the guest STA steal-time record, INVALID_ADDRESS if none
+    pub sta_record: usize,
+    pub vpmu: Vpmu,
+    /// hart id the guest knows this hart by
+    pub vhartid: usize,
+    /// IMSIC guest interrupt file of the vcpu, 0 if the host has no AIA
+    pub vgein: usize,
+    /// set by `inject_virq` on another hart before its IPI, the hart raises VSEIP
+    pub virq_kick: AtomicBool,
+    /// host irqs claimed on this hart that the guest has not claimed yet
+    pub irq_pending: IrqSet,
+    /// host irqs claimed on this hart and not completed yet
+    pub irq_in_service: IrqSet,
+    /// claims of one host source per storm window above which it is throttled, 0 for no limit
+    pub irq_storm_limit: u32,
+    /// misaligned loads and stores of the guest are emulated, see `ZoneConfig`
+    pub emulate_misaligned: bool,
+    /// guest wfi traps to the hypervisor (hstatus.VTW), which parks the hart meanwhile
+    pub trap_wfi: bool,
+    /// guest timer deadline carried by the host timer when the guest has no sstc,
+    /// usize::MAX if none
+    pub guest_timer: usize,
+    /// set by a guest IPI sent to the vcpu before the host IPI, the hart raises VSSIP
+    pub ipi_pending: AtomicBool,
 }
 impl ArchCpu {
     pub fn new(hartid: usize) -> Self {
@@ -33,6 +65,21 @@ impl ArchCpu {
             ssi: 0,
             sti: 0,
             sei: 0,
+            hsm_state: AtomicUsize::new(SBI_HSM_STATE::STOPPED),
+            start_addr: INVALID_ADDRESS,
+            start_opaque: 0,
+            sta_record: INVALID_ADDRESS,
+            vpmu: Vpmu::empty(),
+            vhartid: hartid,
+            vgein: 0,
+            virq_kick: AtomicBool::new(false),
+            irq_pending: IrqSet::new(),
+            irq_in_service: IrqSet::new(),
+            irq_storm_limit: 0,
+            emulate_misaligned: false,
+            trap_wfi: false,
+            guest_timer: usize::MAX,
+            ipi_pending: AtomicBool::new(false),
         }
     }
     pub fn get_hartid(&self) -> usize {
@@ -45,10 +92,10 @@ impl ArchCpu {
         //self.sepc = guest_test as usize as u64;
         write_csr!(CSR_SSCRARCH, self as *const _ as usize); //arch cpu pointer
         self.sepc = entry;
-        self.hstatus = 1 << 7 | 2 << 32; //HSTATUS_SPV | HSTATUS_VSXL_64
+        self.hstatus = 1 << 7 | 2 << 32 | self.vgein << 12 | (self.trap_wfi as usize) << 21; //HSTATUS_SPV | HSTATUS_VSXL_64 | HSTATUS_VGEIN | HSTATUS_VTW
         self.sstatus = 1 << 8 | 1 << 63 | 3 << 13 | 3 << 15; //SPP
         self.stack_top = self.stack_top() as usize;
-        self.x[10] = cpu_id; //cpu id
+        self.x[10] = self.vhartid; //cpu id
         self.x[11] = dtb; //dtb addr
         debug!("CPU {} stack_top: {:#x}", cpu_id, self.stack_top);
         // write_csr!(CSR_STIMECMP, 0);
@@ -56,9 +103,16 @@ impl ArchCpu {
         // write_csr!(CSR_HSTATUS, self.hstatus);
         // write_csr!(CSR_SEPC, self.sepc);
         set_csr!(CSR_HIDELEG, 1 << 2 | 1 << 6 | 1 << 10); //HIDELEG_VSSI | HIDELEG_VSTI | HIDELEG_VSEI
-        set_csr!(CSR_HEDELEG, 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15); //HEDELEG_ECU | HEDELEG_IPF | HEDELEG_LPF | HEDELEG_SPF
-        set_csr!(CSR_HCOUNTEREN, 1 << 1); //HCOUNTEREN_TM
-                                          //In VU-mode, a counter is not readable unless the applicable bits are set in both hcounteren and scounteren.
+
+        // counter overflow goes straight to the zone owning this hart (Sscofpmf)
+        set_csr!(CSR_HIDELEG, 1 << 13); //HIDELEG_LCOFI
+        set_csr!(
+            CSR_HEDELEG,
+            // illegal instructions and breakpoints go straight to the guest
+            1 << 2 | 1 << 3 | 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15
+        ); //HEDELEG_II | HEDELEG_BP | HEDELEG_ECU | HEDELEG_IPF | HEDELEG_LPF | HEDELEG_SPF
+        set_csr!(CSR_HCOUNTEREN, 1 << 1 | self.vpmu.hcounteren()); //HCOUNTEREN_TM
+                                                                   //In VU-mode, a counter is not readable unless the applicable bits are set in both hcounteren and scounteren.
         set_csr!(CSR_SCOUNTEREN, 1 << 1);
         write_csr!(CSR_HTIMEDELTA, 0);
         set_csr!(CSR_HENVCFG, 1 << 63);
@@ -104,6 +158,119 @@ impl ArchCpu {
         clear_csr!(CSR_SIP, 1 << 1);
         debug!("sip*: {:#x}", read_csr!(CSR_SIP));
     }
+    /// Reset the vcpu so that it enters the guest at `entry` with a0 = hartid, a1 = opaque,
+    /// as required by SBI HSM hart_start.
+    pub fn reset(&mut self, entry: usize, opaque: usize) {
+        self.x = [0; 32];
+        self.x[10] = self.vhartid;
+        self.x[11] = opaque;
+        self.sepc = entry;
+        self.hstatus = 1 << 7 | 2 << 32 | self.vgein << 12 | (self.trap_wfi as usize) << 21; //HSTATUS_SPV | HSTATUS_VSXL_64 | HSTATUS_VGEIN | HSTATUS_VTW
+        self.sstatus = 1 << 8 | 1 << 63 | 3 << 13 | 3 << 15; //SPP
+
+        // the guest registers its steal-time record again after a restart
+        self.sta_record = INVALID_ADDRESS;
+        self.vpmu.reset();
+
+        // the hart starts with MMU off and interrupts disabled
+        clear_csr!(CSR_VSSTATUS, 1 << 1); //SSTATUS_SIE
+        write_csr!(CSR_VSIE, 0);
+        write_csr!(CSR_VSTVEC, 0);
+        write_csr!(CSR_VSSCRATCH, 0);
+        write_csr!(CSR_VSEPC, 0);
+        write_csr!(CSR_VSCAUSE, 0);
+        write_csr!(CSR_VSTVAL, 0);
+        write_csr!(CSR_HVIP, 0);
+        write_csr!(CSR_VSATP, 0);
+        // no timer left armed by the previous run of the guest
+        if self.sstc {
+            write_csr!(CSR_VSTIMECMP, usize::MAX);
+        }
+        self.guest_timer = usize::MAX;
+        // stop() masked both, the host timer also carries the guest timer
+        set_csr!(CSR_SIE, 1 << 9 | 1 << 5); //SEIE STIE
+
+        // the guest image may have been reloaded by another hart
+        unsafe {
+            core::arch::asm!("fence.i");
+        }
+    }
+    /// Resume a non-retentive suspended hart at `entry`, with the registers hart_start gives.
+    pub fn resume(&mut self, entry: usize, opaque: usize) {
+        self.x[10] = self.vhartid;
+        self.x[11] = opaque;
+        self.sepc = entry;
+        self.sstatus = 1 << 8 | 1 << 63 | 3 << 13 | 3 << 15; //SPP
+        get_cpu_data(self.hartid).cpu_on_entry = entry;
+        clear_csr!(CSR_VSSTATUS, 1 << 1); //SSTATUS_SIE
+        write_csr!(CSR_VSATP, 0);
+    }
+    /// Wait in wfi until an interrupt for this hart is pending, for the SBI suspend calls
+    /// and the trapped guest wfi, the hart is in HSM state `state` meanwhile.
+    /// Returns false if the zone asked the hart to stop meanwhile.
+    pub fn wait_for_interrupt(&mut self, state: usize) -> bool {
+        // an MSI to the guest interrupt file must wake up the wfi
+        if self.vgein != 0 {
+            write_csr!(CSR_HGEIE, 1 << self.vgein);
+            set_csr!(CSR_HIE, 1 << 12); //SGEIE
+        }
+        let woken = loop {
+            if self.hsm_state.load(Ordering::Acquire) != state {
+                break false;
+            }
+            let pending = read_csr!(CSR_SIP) & read_csr!(CSR_SIE)
+                | read_csr!(CSR_HIP) & (1 << 2 | 1 << 6 | 1 << 10); //VSSIP VSTIP VSEIP
+            if pending != 0 {
+                break true;
+            }
+            unsafe {
+                core::arch::asm!("wfi");
+            }
+        };
+        clear_csr!(CSR_HIE, 1 << 12); //SGEIE
+        write_csr!(CSR_HGEIE, 0);
+        woken
+    }
+    /// Park this hart until another hart of the same zone starts it by SBI HSM hart_start.
+    pub fn wait_for_start(&mut self) {
+        loop {
+            if self.hsm_state.load(Ordering::Acquire) == SBI_HSM_STATE::START_PENDING {
+                let (entry, opaque) = (self.start_addr, self.start_opaque);
+                // the start may be cancelled if the zone is being reset
+                if self
+                    .hsm_state
+                    .compare_exchange(
+                        SBI_HSM_STATE::START_PENDING,
+                        SBI_HSM_STATE::STARTED,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok()
+                {
+                    self.reset(entry, opaque);
+                    return;
+                }
+            }
+            self.idle();
+        }
+    }
+    /// SBI HSM hart_stop: park this hart until it is started again, then enter the guest.
+    pub fn stop(&mut self) -> ! {
+        // mask timer and external interrupts, so that only the start IPI wakes us up
+        clear_csr!(CSR_SIE, 1 << 9 | 1 << 5); //SEIE STIE
+        write_csr!(CSR_HVIP, 0);
+        self.ipi_pending.store(false, Ordering::Release);
+        if self.sstc {
+            write_csr!(CSR_VSTIMECMP, usize::MAX);
+        }
+        self.vpmu.reset();
+        info!("CPU{} stopped", self.hartid);
+        self.hsm_state
+            .store(SBI_HSM_STATE::STOPPED, Ordering::Release);
+        self.wait_for_start();
+        self.run();
+        unreachable!()
+    }
 }
 // fn guest_test(id: usize, args: [usize; 3]) -> isize {
 //     let mut ret: isize;
@@ -3,6 +3,7 @@ pub const CSR_SCAUSE: u64 = 0x142;
 pub const CSR_STVEC: u64 = 0x105;
 pub const CSR_SCOUNTEREN: u64 = 0x106;
 pub const CSR_SEPC: u64 = 0x141;
+pub const CSR_STVAL: u64 = 0x143;
 pub const CSR_SSTATUS: u64 = 0x100;
 pub const CSR_SSCRARCH: u64 = 0x140;
 pub const CSR_SIE: u64 = 0x104;
@@ -41,6 +42,24 @@ pub const CSR_STIMECMPH: u64 = 0x15D;
 pub const CSR_VSTIMECMP: u64 = 0x24D;
 pub const CSR_VSTIMECMPH: u64 = 0x25D;
 
+/* AIA Extension */
+pub const CSR_SISELECT: u64 = 0x150;
+pub const CSR_SIREG: u64 = 0x151;
+pub const CSR_STOPEI: u64 = 0x15C;
+pub const CSR_STOPI: u64 = 0xDB0;
+pub const CSR_VSISELECT: u64 = 0x250;
+pub const CSR_VSIREG: u64 = 0x251;
+pub const CSR_VSTOPEI: u64 = 0x25C;
+pub const CSR_VSTOPI: u64 = 0xEB0;
+pub const CSR_HVICTL: u64 = 0x609;
+
+/* sstatus/vsstatus fields */
+pub const SSTATUS_SIE: usize = 1 << 1;
+pub const SSTATUS_SPIE: usize = 1 << 5;
+pub const SSTATUS_SPP: usize = 1 << 8;
+/* hstatus fields */
+pub const HSTATUS_SPVP: usize = 1 << 8;
+
 macro_rules! read_csr {
     ($csr_number:expr) => {
         {
@@ -95,3 +114,22 @@ macro_rules! set_csr {
     };
 }
 pub(crate) use set_csr;
+
+macro_rules! swap_csr {
+    ($csr_number:expr, $value: expr) => {
+        {
+            let mut old: usize;
+            unsafe{
+                let v: usize = $value;
+                ::core::arch::asm!(
+                "csrrw {old}, {csr}, {value}",
+                old = out(reg) old,
+                value = in(reg) v,
+                csr = const $csr_number,
+                options(nomem, nostack),
+            );}
+            old
+        }
+    };
+}
+pub(crate) use swap_csr;
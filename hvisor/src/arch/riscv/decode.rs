@@ -0,0 +1,163 @@
+//! Decoding of the guest loads and stores trapped by stage-2 faults, for MMIO emulation.
+use super::cpu::ArchCpu;
+use super::guestmem::hlvxhu;
+use crate::error::HvResult;
+use crate::memory::GuestVirtAddr;
+
+const OPCODE_LOAD: u32 = 0x03;
+const OPCODE_STORE: u32 = 0x23;
+
+/// a decoded guest load or store
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    /// destination register of a load, source register of a store
+    pub reg: usize,
+    /// access size in bytes
+    pub width: usize,
+    /// the loaded value is sign extended
+    pub signed: bool,
+    pub is_write: bool,
+    /// length of the trapping instruction, to step over it
+    pub inst_len: usize,
+}
+impl MmioAccess {
+    const fn load(reg: u32, width: usize, signed: bool, inst_len: usize) -> Self {
+        Self {
+            reg: reg as usize,
+            width,
+            signed,
+            is_write: false,
+            inst_len,
+        }
+    }
+    const fn store(reg: u32, width: usize, inst_len: usize) -> Self {
+        Self {
+            reg: reg as usize,
+            width,
+            signed: false,
+            is_write: true,
+            inst_len,
+        }
+    }
+    /// the bytes of `value` covered by the access
+    pub fn truncate(&self, value: usize) -> usize {
+        match self.width {
+            8 => value,
+            width => value & ((1 << (width * 8)) - 1),
+        }
+    }
+    /// register value of a load returning `value`
+    pub fn extend(&self, value: usize) -> usize {
+        let value = self.truncate(value);
+        if !self.signed || self.width == 8 {
+            return value;
+        }
+        let shift = 64 - self.width * 8;
+        (((value << shift) as isize) >> shift) as usize
+    }
+}
+
+/// decode the load or store that faulted at the sepc of `current_cpu`, `htinst` is the
+/// trapped instruction written by the hardware, read from guest memory when it is 0
+pub fn decode_mmio_access(current_cpu: &ArchCpu, htinst: usize) -> HvResult<MmioAccess> {
+    if htinst != 0 {
+        return decode_transformed(htinst as u32);
+    }
+    let sepc = current_cpu.sepc;
+    let inst = read_inst(current_cpu, sepc).map_err(|cause| {
+        hv_err!(
+            EFAULT,
+            format!("fetch of {:#x} raises exception {}", sepc, cause)
+        )
+    })?;
+    decode_access(inst).ok_or_else(|| {
+        hv_err!(
+            EINVAL,
+            format!("no load or store at {:#x}: {:#x}", sepc, inst)
+        )
+    })
+}
+/// decode the non-zero `htinst` of a guest load or store
+fn decode_transformed(htinst: u32) -> HvResult<MmioAccess> {
+    if htinst & 1 == 0 {
+        // 0x2000/0x2020/0x3000/0x3020: implicit access of the guest page table walk
+        return hv_result_err!(
+            EFAULT,
+            format!("fault on 1st stage page table walk: {:#x}", htinst)
+        );
+    }
+    // transformed instruction: the 32-bit form with the address fields cleared, bit 1 is
+    // cleared when the trapping instruction was compressed
+    let inst_len = if htinst & 0b10 == 0 { 2 } else { 4 };
+    let mut access = decode_access(htinst | 0b10)
+        .ok_or_else(|| hv_err!(EINVAL, format!("unexpected htinst {:#x}", htinst)))?;
+    access.inst_len = inst_len;
+    Ok(access)
+}
+/// decode a raw instruction, None if it is not a load or store
+pub fn decode_access(inst: u32) -> Option<MmioAccess> {
+    if inst & 0b11 != 0b11 {
+        return decode_compressed(inst as u16);
+    }
+    let funct3 = (inst >> 12) & 0b111;
+    match inst & 0x7f {
+        OPCODE_LOAD => {
+            let rd = (inst >> 7) & 0x1f;
+            let (width, signed) = match funct3 {
+                0 => (1, true),  // lb
+                1 => (2, true),  // lh
+                2 => (4, true),  // lw
+                3 => (8, false), // ld
+                4 => (1, false), // lbu
+                5 => (2, false), // lhu
+                6 => (4, false), // lwu
+                _ => return None,
+            };
+            Some(MmioAccess::load(rd, width, signed, 4))
+        }
+        OPCODE_STORE => {
+            let rs2 = (inst >> 20) & 0x1f;
+            let width = match funct3 {
+                0 => 1, // sb
+                1 => 2, // sh
+                2 => 4, // sw
+                3 => 8, // sd
+                _ => return None,
+            };
+            Some(MmioAccess::store(rs2, width, 4))
+        }
+        _ => None,
+    }
+}
+fn decode_compressed(inst: u16) -> Option<MmioAccess> {
+    let inst = inst as u32;
+    let funct3 = (inst >> 13) & 0b111;
+    // rd' and rs2' of the CL/CS formats, x8~x15
+    let creg = ((inst >> 2) & 0b111) + 8;
+    let rd = (inst >> 7) & 0x1f;
+    let rs2 = (inst >> 2) & 0x1f;
+    match (inst & 0b11, funct3) {
+        (0b00, 0b010) => Some(MmioAccess::load(creg, 4, true, 2)), // c.lw
+        (0b00, 0b011) => Some(MmioAccess::load(creg, 8, false, 2)), // c.ld
+        (0b00, 0b110) => Some(MmioAccess::store(creg, 4, 2)),      // c.sw
+        (0b00, 0b111) => Some(MmioAccess::store(creg, 8, 2)),      // c.sd
+        (0b10, 0b010) if rd != 0 => Some(MmioAccess::load(rd, 4, true, 2)), // c.lwsp
+        (0b10, 0b011) if rd != 0 => Some(MmioAccess::load(rd, 8, false, 2)), // c.ldsp
+        (0b10, 0b110) => Some(MmioAccess::store(rs2, 4, 2)),       // c.swsp
+        (0b10, 0b111) => Some(MmioAccess::store(rs2, 8, 2)),       // c.sdsp
+        _ => None,
+    }
+}
+/// read the guest instruction at `addr` with hlvx, the error is the exception the fetch
+/// raises in the guest
+pub fn read_inst(current_cpu: &ArchCpu, addr: GuestVirtAddr) -> Result<u32, usize> {
+    //
+    //  Read 16 bits at a time to make sure the access is aligned. If the instruction is not
+    //  compressed, read the following 16-bits, which may be on the next page.
+    //
+    let mut ins = hlvxhu(current_cpu, addr)? as u32;
+    if (ins & 0b11) == 3 {
+        ins |= (hlvxhu(current_cpu, addr + 2)? as u32) << 16;
+    }
+    Ok(ins)
+}
@@ -0,0 +1,141 @@
+//! Accesses of guest virtual memory with the hypervisor virtual-machine loads and stores
+//! (hlv/hlvx/hsv), done with the privilege the guest trapped from.
+//!
+//! The hardware translates the address through both stages with the checks the guest
+//! would get. A fault of the access is caught by a temporary trap vector and given back as
+//! the exception the guest gets; a guest page fault on demand RAM populates it and the
+//! access is retried.
+use super::cpu::ArchCpu;
+use super::csr::*;
+use super::trap::ExceptionType;
+use crate::memory::{GuestPhysAddr, GuestVirtAddr};
+use crate::percpu::get_cpu_data;
+use core::arch::{asm, global_asm};
+
+// trap vector installed around one access, sstatus.SIE is clear in the hypervisor: a1 gets
+// scause, a2 htval and the faulting access is skipped, it is never compressed
+global_asm!(
+    ".text",
+    ".global _guest_access_trap",
+    ".align 2",
+    "_guest_access_trap:",
+    "csrr a1, sepc",
+    "addi a1, a1, 4",
+    "csrw sepc, a1",
+    "csrr a1, scause",
+    "csrr a2, htval",
+    "sret",
+);
+extern "C" {
+    fn _guest_access_trap();
+}
+/// scause of an access that did not trap
+const NO_TRAP: usize = usize::MAX;
+
+/// a trapped access
+#[derive(Debug, Clone, Copy)]
+struct AccessTrap {
+    cause: usize,
+    /// faulting guest physical address of a guest page fault
+    gpa: GuestPhysAddr,
+}
+
+/// run the hypervisor load or store `insn` on {addr} with {value} under the temporary trap
+/// vector, giving {value} back
+macro_rules! trapped_access {
+    ($insn:literal, $addr:expr, $value:expr) => {{
+        let mut value: usize = $value;
+        let mut cause = NO_TRAP;
+        let mut htval: usize = 0;
+        unsafe {
+            asm!(
+                "csrrw {vec}, stvec, {vec}",
+                $insn,
+                "csrw stvec, {vec}",
+                vec = inout(reg) _guest_access_trap as usize => _,
+                addr = in(reg) $addr,
+                value = inout(reg) value,
+                inout("a1") cause,
+                inout("a2") htval,
+            );
+        }
+        match cause {
+            NO_TRAP => Ok(value),
+            cause => Err(AccessTrap {
+                cause,
+                gpa: htval << 2,
+            }),
+        }
+    }};
+}
+
+/// run `access` with hstatus.SPVP set to the privilege the guest trapped from, populating
+/// demand RAM on its guest page faults; the error is the exception the guest gets
+fn guest_access(
+    current_cpu: &ArchCpu,
+    mut access: impl FnMut() -> Result<usize, AccessTrap>,
+) -> Result<usize, usize> {
+    let hstatus = read_csr!(CSR_HSTATUS);
+    let spvp = if current_cpu.sstatus & SSTATUS_SPP != 0 {
+        HSTATUS_SPVP
+    } else {
+        0
+    };
+    write_csr!(CSR_HSTATUS, (hstatus & !HSTATUS_SPVP) | spvp);
+    let result = loop {
+        let trap = match access() {
+            Ok(value) => break Ok(value),
+            Err(trap) => trap,
+        };
+        let access_fault = match trap.cause {
+            ExceptionType::INST_GUEST_PAGE_FAULT => ExceptionType::INST_ACCESS_FAULT,
+            ExceptionType::LOAD_GUEST_PAGE_FAULT => ExceptionType::LOAD_ACCESS_FAULT,
+            ExceptionType::STORE_GUEST_PAGE_FAULT => ExceptionType::STORE_ACCESS_FAULT,
+            cause => break Err(cause),
+        };
+        let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+        let mut zone = zone.write();
+        if !zone.demand_ram || !zone.is_ram(trap.gpa) {
+            break Err(access_fault);
+        }
+        if let Err(e) = zone.populate_ram(trap.gpa) {
+            error!("zone RAM at {:#x} not populated: {:?}", trap.gpa, e);
+            break Err(access_fault);
+        }
+    };
+    // the trap vector also overwrote hstatus.SPV
+    write_csr!(CSR_HSTATUS, hstatus);
+    result
+}
+/// load the byte at `addr`, hlv.bu
+pub fn hlvbu(current_cpu: &ArchCpu, addr: GuestVirtAddr) -> Result<u8, usize> {
+    guest_access(current_cpu, || {
+        trapped_access!(".insn r 0x73, 0x4, 0x30, {value}, {addr}, x1", addr, 0)
+    })
+    .map(|value| value as u8)
+}
+/// load the aligned doubleword at `addr`, hlv.d
+pub fn hlvd(current_cpu: &ArchCpu, addr: GuestVirtAddr) -> Result<u64, usize> {
+    guest_access(current_cpu, || {
+        trapped_access!(".insn r 0x73, 0x4, 0x36, {value}, {addr}, x0", addr, 0)
+    })
+    .map(|value| value as u64)
+}
+/// load the instruction halfword at `addr` with execute permission, hlvx.hu
+pub fn hlvxhu(current_cpu: &ArchCpu, addr: GuestVirtAddr) -> Result<u16, usize> {
+    guest_access(current_cpu, || {
+        trapped_access!(".insn r 0x73, 0x4, 0x32, {value}, {addr}, x3", addr, 0)
+    })
+    .map(|value| value as u16)
+}
+/// store the byte `value` at `addr`, hsv.b
+pub fn hsvb(current_cpu: &ArchCpu, addr: GuestVirtAddr, value: u8) -> Result<(), usize> {
+    guest_access(current_cpu, || {
+        trapped_access!(
+            ".insn r 0x73, 0x4, 0x31, x0, {addr}, {value}",
+            addr,
+            value as usize
+        )
+    })
+    .map(|_| ())
+}
@@ -0,0 +1,205 @@
+//! Host interrupt controller backends, picked from the `compatible` of the host device tree.
+use super::aia::{
+    host_aia, init_aia, APLIC_CLRIENUM, APLIC_DOMAINCFG, APLIC_DOMAINCFG_DM, APLIC_DOMAINCFG_IE,
+    APLIC_SETIENUM,
+};
+use super::cpu::ArchCpu;
+use super::csr::*;
+use super::plic::{host_plic, init_plic, plic_contexts, IRQ_S_EXT, PLIC_MAX_IRQ};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
+use riscv::register::hvip;
+use spin::Once;
+
+pub const PLIC_COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+pub const APLIC_COMPATIBLE: &[&str] = &["riscv,aplic"];
+
+pub trait IrqChip: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// program the host controller, called once by the primary hart
+    fn init(&self);
+    /// take the next pending external irq of the current hart `hart`, None if none left
+    fn claim(&self, hart: usize) -> Option<u32>;
+    /// signal the end of service of a claimed irq
+    fn complete(&self, hart: usize, irq: u32);
+    /// route source `irq` to `hart` or mask it
+    fn enable(&self, hart: usize, irq: u32, enable: bool);
+    /// hand a claimed irq to the guest running on the current hart
+    fn inject(&self, current_cpu: &mut ArchCpu, irq: u32);
+    /// mask source `irq` on all harts, whatever the guest programs meanwhile
+    fn mask(&self, irq: u32);
+    fn unmask(&self, irq: u32);
+}
+
+pub static IRQCHIP: Once<Box<dyn IrqChip>> = Once::new();
+
+pub fn irqchip<'a>() -> &'a dyn IrqChip {
+    IRQCHIP
+        .get()
+        .expect("Uninitialized hypervisor irqchip!")
+        .as_ref()
+}
+const IRQ_SET_WORDS: usize = (PLIC_MAX_IRQ + 1) / 32;
+/// lock-free set of interrupt sources, used per hart on the interrupt path
+#[derive(Debug)]
+pub struct IrqSet {
+    words: [AtomicU32; IRQ_SET_WORDS],
+}
+impl IrqSet {
+    pub const fn new() -> Self {
+        const EMPTY: AtomicU32 = AtomicU32::new(0);
+        Self {
+            words: [EMPTY; IRQ_SET_WORDS],
+        }
+    }
+    pub fn insert(&self, irq: usize) {
+        if irq <= PLIC_MAX_IRQ {
+            self.words[irq / 32].fetch_or(1 << (irq % 32), Ordering::AcqRel);
+        }
+    }
+    /// remove `irq`, returns whether it was in the set
+    pub fn remove(&self, irq: usize) -> bool {
+        if irq > PLIC_MAX_IRQ {
+            return false;
+        }
+        let bit = 1 << (irq % 32);
+        self.words[irq / 32].fetch_and(!bit, Ordering::AcqRel) & bit != 0
+    }
+    pub fn contains(&self, irq: usize) -> bool {
+        irq <= PLIC_MAX_IRQ && self.words[irq / 32].load(Ordering::Acquire) & (1 << (irq % 32)) != 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.words
+            .iter()
+            .all(|word| word.load(Ordering::Acquire) == 0)
+    }
+    pub fn clear(&self) {
+        self.words
+            .iter()
+            .for_each(|word| word.store(0, Ordering::Release));
+    }
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            let bits = word.load(Ordering::Acquire);
+            (0..32)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| i * 32 + bit)
+        })
+    }
+}
+/// true if one of the `compatible` strings of `node` is in `with`
+pub fn is_compatible(node: &fdt::node::FdtNode, with: &[&str]) -> bool {
+    node.compatible().map_or(false, |compatible| {
+        compatible.all().any(|c| with.contains(&c))
+    })
+}
+/// probe the host interrupt controller, AIA is preferred when the host has both
+pub fn init_irqchip(host_fdt: &fdt::Fdt) {
+    let chip: Box<dyn IrqChip> =
+        if host_fdt.find_compatible(APLIC_COMPATIBLE).is_some() && init_aia(host_fdt) {
+            Box::new(AplicChip)
+        } else if let Some(plic) = host_fdt.find_compatible(PLIC_COMPATIBLE) {
+            let reg = plic.reg().unwrap().next().unwrap();
+            init_plic(
+                reg.starting_address as usize,
+                reg.size.unwrap(),
+                plic_contexts(host_fdt, &plic),
+            );
+            Box::new(PlicChip)
+        } else {
+            panic!("no supported interrupt controller in the host device tree");
+        };
+    info!("host irqchip: {}", chip.name());
+    chip.init();
+    IRQCHIP.call_once(|| chip);
+}
+
+/// PLIC, the guest claims and completes through the vplic emulation
+pub struct PlicChip;
+impl IrqChip for PlicChip {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+    fn init(&self) {
+        // let every priority through on the S-mode contexts, the guests set their own
+        let plic = host_plic();
+        plic.contexts
+            .iter()
+            .enumerate()
+            .filter(|(_, &(_, irq))| irq == IRQ_S_EXT)
+            .for_each(|(context, _)| plic.set_threshold(context, 0));
+    }
+    fn claim(&self, hart: usize) -> Option<u32> {
+        let plic = host_plic();
+        let irq = plic.claim(plic.smode_context(hart));
+        (irq != 0).then_some(irq)
+    }
+    fn complete(&self, hart: usize, irq: u32) {
+        let plic = host_plic();
+        plic.complete(plic.smode_context(hart), irq);
+    }
+    fn enable(&self, hart: usize, irq: u32, enable: bool) {
+        let plic = host_plic();
+        plic.set_enable_bit(plic.smode_context(hart), irq as usize, enable);
+    }
+    fn inject(&self, current_cpu: &mut ArchCpu, irq: u32) {
+        // the guest reads it back from its claim register and completes it
+        current_cpu.irq_in_service.insert(irq as usize);
+        current_cpu.irq_pending.insert(irq as usize);
+        // set external interrupt pending, which trigger guest interrupt
+        unsafe { hvip::set_vseip() };
+    }
+    fn mask(&self, irq: u32) {
+        host_plic().mask(irq as usize);
+    }
+    fn unmask(&self, irq: u32) {
+        host_plic().unmask(irq as usize);
+    }
+}
+
+/// APLIC in MSI mode with IMSIC, the zone devices reach the guest interrupt files
+/// directly, so irqs claimed here are only the ones sent to the hypervisor S file
+pub struct AplicChip;
+impl IrqChip for AplicChip {
+    fn name(&self) -> &'static str {
+        "aplic"
+    }
+    fn init(&self) {
+        host_aia()
+            .unwrap()
+            .aplic
+            .write(APLIC_DOMAINCFG, APLIC_DOMAINCFG_IE | APLIC_DOMAINCFG_DM);
+    }
+    fn claim(&self, _hart: usize) -> Option<u32> {
+        // a single swap of stopei claims the top interrupt of the S file, a separate read
+        // and write could claim a higher one arriving in between and lose it
+        match (swap_csr!(CSR_STOPEI, 0) >> 16) & 0x7ff {
+            0 => None,
+            identity => Some(identity as u32),
+        }
+    }
+    fn complete(&self, _hart: usize, _irq: u32) {
+        // MSIs are done once claimed from stopei
+    }
+    fn enable(&self, _hart: usize, irq: u32, enable: bool) {
+        let aplic = &host_aia().unwrap().aplic;
+        aplic.write(
+            if enable {
+                APLIC_SETIENUM
+            } else {
+                APLIC_CLRIENUM
+            },
+            irq,
+        );
+    }
+    fn inject(&self, current_cpu: &mut ArchCpu, irq: u32) {
+        // the hypervisor owns no device
+        warn!("CPU{} unexpected host MSI {}", current_cpu.hartid, irq);
+    }
+    fn mask(&self, irq: u32) {
+        self.enable(0, irq, false);
+    }
+    fn unmask(&self, irq: u32) {
+        self.enable(0, irq, true);
+    }
+}
@@ -0,0 +1,132 @@
+//! Interrupt storm throttling of the host sources passed through to zones.
+//!
+//! Each host source claimed by `handle_eirq` is counted per window. A source going over
+//! the limit of the zone running on the hart is masked at the irqchip and unmasked again
+//! after a backoff that doubles while the source keeps storming. The masking hart arms its
+//! host timer for the end of the backoff.
+use super::cpu::ArchCpu;
+use super::irqchip::irqchip;
+use super::plic::PLIC_MAX_IRQ;
+use super::timer::{arm_host_timer, get_time, CLOCK_FREQ};
+use crate::percpu::get_cpu_data;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// rate window: 10ms in timer ticks
+pub const IRQ_STORM_WINDOW: usize = CLOCK_FREQ / 100;
+/// a storming source is masked for at most 2^8 windows
+pub const IRQ_STORM_MAX_BACKOFF: u32 = 8;
+
+struct IrqRate {
+    window_start: AtomicUsize,
+    count: AtomicU32,
+    /// time the source gets unmasked, 0 if not masked
+    masked_until: AtomicUsize,
+    last_unmask: AtomicUsize,
+    backoff: AtomicU32,
+}
+impl IrqRate {
+    const fn new() -> Self {
+        Self {
+            window_start: AtomicUsize::new(0),
+            count: AtomicU32::new(0),
+            masked_until: AtomicUsize::new(0),
+            last_unmask: AtomicUsize::new(0),
+            backoff: AtomicU32::new(0),
+        }
+    }
+}
+const IRQ_RATE_INIT: IrqRate = IrqRate::new();
+static IRQ_RATES: [IrqRate; PLIC_MAX_IRQ + 1] = [IRQ_RATE_INIT; PLIC_MAX_IRQ + 1];
+/// number of sources masked by throttling, so that polling is free when there is none
+static MASKED_IRQS: AtomicUsize = AtomicUsize::new(0);
+
+/// count one claim of host source `irq` on the current hart, mask it if it storms
+pub fn irq_storm_account(current_cpu: &ArchCpu, irq: u32) {
+    let limit = current_cpu.irq_storm_limit;
+    if limit == 0 || irq as usize > PLIC_MAX_IRQ {
+        return;
+    }
+    let rate = &IRQ_RATES[irq as usize];
+    let now = get_time();
+    if now - rate.window_start.load(Ordering::Relaxed) >= IRQ_STORM_WINDOW {
+        rate.window_start.store(now, Ordering::Relaxed);
+        rate.count.store(1, Ordering::Relaxed);
+        return;
+    }
+    if rate.count.fetch_add(1, Ordering::Relaxed) < limit
+        || rate.masked_until.load(Ordering::Acquire) != 0
+    {
+        return;
+    }
+    // storming again soon after the last unmask, keep it masked longer
+    let backoff = rate.backoff.load(Ordering::Relaxed);
+    let backoff =
+        if now - rate.last_unmask.load(Ordering::Relaxed) < (IRQ_STORM_WINDOW << backoff) * 2 {
+            (backoff + 1).min(IRQ_STORM_MAX_BACKOFF)
+        } else {
+            0
+        };
+    rate.backoff.store(backoff, Ordering::Relaxed);
+    let duration = IRQ_STORM_WINDOW << backoff;
+    if rate
+        .masked_until
+        .compare_exchange(0, now + duration, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+    MASKED_IRQS.fetch_add(1, Ordering::AcqRel);
+    irqchip().mask(irq);
+    // the guest may run with sstc and never use the host timer, make it fire for the unmask
+    arm_host_timer(current_cpu);
+    let vmid = get_cpu_data(current_cpu.hartid)
+        .zone
+        .as_ref()
+        .map_or(usize::MAX, |zone| zone.read().vmid);
+    warn!(
+        "zone {} irq {} storm: more than {} irqs in {}ms, masked for {}ms",
+        vmid,
+        irq,
+        limit,
+        IRQ_STORM_WINDOW * 1000 / CLOCK_FREQ,
+        duration * 1000 / CLOCK_FREQ
+    );
+}
+/// earliest time a throttled source gets unmasked, usize::MAX if none is masked
+pub fn irq_storm_deadline() -> usize {
+    if MASKED_IRQS.load(Ordering::Acquire) == 0 {
+        return usize::MAX;
+    }
+    IRQ_RATES
+        .iter()
+        .map(|rate| rate.masked_until.load(Ordering::Acquire))
+        .filter(|&until| until != 0)
+        .min()
+        .unwrap_or(usize::MAX)
+}
+/// unmask the throttled sources whose backoff is over
+pub fn irq_storm_poll() {
+    if MASKED_IRQS.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let now = get_time();
+    for (irq, rate) in IRQ_RATES.iter().enumerate() {
+        let until = rate.masked_until.load(Ordering::Acquire);
+        if until == 0 || now < until {
+            continue;
+        }
+        if rate
+            .masked_until
+            .compare_exchange(until, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            continue;
+        }
+        rate.last_unmask.store(now, Ordering::Relaxed);
+        rate.window_start.store(now, Ordering::Relaxed);
+        rate.count.store(0, Ordering::Relaxed);
+        MASKED_IRQS.fetch_sub(1, Ordering::AcqRel);
+        irqchip().unmask(irq as u32);
+        info!("irq {} unmasked after storm", irq);
+    }
+}
@@ -0,0 +1,96 @@
+//! Emulation of the misaligned guest loads and stores, which are not delegated to VS mode.
+//!
+//! The access is split in byte accesses done with hlv/hsv, so that they go through the
+//! guest translation with the privilege the guest trapped from. A byte that faults raises
+//! the exception in the guest, a store first checks every page it touches so that it is
+//! either done or leaves the memory untouched.
+use super::cpu::ArchCpu;
+use super::csr::*;
+use super::decode::{decode_mmio_access, MmioAccess};
+use super::guestmem::{hlvbu, hsvb};
+use super::trap::{inject_exception, ExceptionType};
+use crate::consts::PAGE_SIZE;
+use crate::memory::GuestVirtAddr;
+
+/// handle a LOAD_MISALIGNED or STORE_MISALIGNED exception `trap_code` of the guest
+pub fn misaligned_handler(current_cpu: &mut ArchCpu, trap_code: usize) {
+    let addr: GuestVirtAddr = read_csr!(CSR_STVAL);
+    if !current_cpu.emulate_misaligned {
+        inject_exception(current_cpu, trap_code, addr);
+        return;
+    }
+    let access = match decode_mmio_access(current_cpu, read_csr!(CSR_HTINST)) {
+        Ok(access) => access,
+        Err(e) => {
+            error!(
+                "CPU {} bad misaligned access at {:#x}: {:?}",
+                current_cpu.hartid, addr, e
+            );
+            inject_exception(current_cpu, trap_code, addr);
+            return;
+        }
+    };
+    trace!("misaligned access at {:#x}: {:#x?}", addr, access);
+    let result = if access.is_write {
+        misaligned_store(current_cpu, addr, &access)
+    } else {
+        misaligned_load(current_cpu, addr, &access).map(|value| {
+            if access.reg != 0 {
+                current_cpu.x[access.reg] = value;
+            }
+        })
+    };
+    match result {
+        Ok(()) => current_cpu.sepc += access.inst_len,
+        Err((cause, gva)) => {
+            debug!(
+                "CPU {} misaligned access at {:#x} raises {}",
+                current_cpu.hartid, gva, cause
+            );
+            inject_exception(current_cpu, cause, gva);
+        }
+    }
+}
+/// register value of the load `access` at `addr`, the error is the exception and the
+/// faulting address
+fn misaligned_load(
+    current_cpu: &ArchCpu,
+    addr: GuestVirtAddr,
+    access: &MmioAccess,
+) -> Result<usize, (usize, GuestVirtAddr)> {
+    let mut value = 0;
+    for i in 0..access.width {
+        let byte = hlvbu(current_cpu, addr + i).map_err(|cause| (cause, addr + i))?;
+        value |= (byte as usize) << (i * 8);
+    }
+    Ok(access.extend(value))
+}
+/// store the register of `access` at `addr`, the error is the exception and the faulting
+/// address
+fn misaligned_store(
+    current_cpu: &ArchCpu,
+    addr: GuestVirtAddr,
+    access: &MmioAccess,
+) -> Result<(), (usize, GuestVirtAddr)> {
+    // write back a byte of each page, the store fails there if it fails at all
+    let last = addr + access.width - 1;
+    let probes = [addr, last & !(PAGE_SIZE - 1)];
+    for &gva in probes.iter().filter(|&&gva| gva >= addr) {
+        hlvbu(current_cpu, gva)
+            .and_then(|byte| hsvb(current_cpu, gva, byte))
+            .map_err(|cause| (store_cause(cause), gva))?;
+    }
+    let value = current_cpu.x[access.reg];
+    for i in 0..access.width {
+        hsvb(current_cpu, addr + i, (value >> (i * 8)) as u8).map_err(|cause| (cause, addr + i))?;
+    }
+    Ok(())
+}
+/// the exception of a store raising the load exception `cause`
+fn store_cause(cause: usize) -> usize {
+    match cause {
+        ExceptionType::LOAD_ACCESS_FAULT => ExceptionType::STORE_ACCESS_FAULT,
+        ExceptionType::LOAD_PAGE_FAULT => ExceptionType::STORE_PAGE_FAULT,
+        cause => cause,
+    }
+}
@@ -1,9 +1,18 @@
+pub mod aia;
 pub mod cpu;
 pub mod csr;
+pub mod decode;
+pub mod guestmem;
+pub mod irqchip;
+pub mod irqstorm;
+pub mod misaligned;
 pub mod plic;
+pub mod pmu;
 pub mod s1pt;
+pub mod s2fault;
 pub mod s2pt;
 pub mod sbi;
 pub mod timer;
 pub mod trap;
+pub mod vcsr;
 pub mod entry;
@@ -1,10 +1,16 @@
 use core::ops::Add;
 
+use super::irqchip::{irqchip, IrqSet};
 use crate::arch::riscv::csr::*;
-use crate::{cpu::ArchCpu, memory::GuestPhysAddr};
+use crate::cpu::ArchCpu;
+use crate::error::HvResult;
+use crate::mmio::MmioDevice;
+use crate::percpu::{get_cpu_data, this_cpu_id};
+use crate::zone::Zone;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 use riscv::register::{hvip, sie};
-use riscv_decode::Instruction;
-use spin::{Once, RwLock};
+use spin::{Mutex, Once};
 // PLIC Memory Map
 //  base + 0x000000: Reserved (interrupt source 0 does not exist)
 //  base + 0x000004: Interrupt source 1 priority
@@ -52,34 +58,109 @@ pub const PLIC_ENABLE_BASE: usize = 0x2000;
 pub const PLIC_GLOBAL_SIZE: usize = 0x200000;
 pub const PLIC_TOTAL_SIZE: usize = 0x400000;
 pub const PLIC_MAX_CONTEXT: usize = 64;
-pub static PLIC: Once<RwLock<Plic>> = Once::new();
+/// host PLIC, immutable after init so that the interrupt path takes no lock
+pub static PLIC: Once<Plic> = Once::new();
+/// local interrupt numbers of the hart contexts in interrupts-extended
+pub const IRQ_S_EXT: u32 = 9;
+pub const IRQ_M_EXT: u32 = 11;
 
-pub fn host_plic<'a>() -> &'a RwLock<Plic> {
+pub fn host_plic<'a>() -> &'a Plic {
     PLIC.get().expect("Uninitialized hypervisor plic!")
 }
-pub fn init_plic(plic_base: usize, plic_size: usize) {
-    let plic = Plic::new(plic_base, plic_size);
-    PLIC.call_once(|| RwLock::new(plic));
+pub fn init_plic(plic_base: usize, plic_size: usize, contexts: Vec<(usize, u32)>) {
+    let mut plic = Plic::new(plic_base, plic_size);
+    plic.contexts = contexts;
+    PLIC.call_once(|| plic);
 }
 pub struct Plic {
     pub base: usize,
     pub size: usize,
-    pub claim_complete: [u32; PLIC_MAX_CONTEXT],
+    /// hart and local interrupt of each context, from the host interrupts-extended
+    pub contexts: Vec<(usize, u32)>,
+    /// storm throttling state, only used on the slow paths
+    mask: Mutex<PlicMask>,
+}
+/// sources masked by storm throttling, and the priority they get back on unmask
+struct PlicMask {
+    masked: [u32; PLIC_MAX_IRQ / 32 + 1],
+    saved_priority: [u32; PLIC_MAX_IRQ + 1],
 }
 impl Plic {
     pub fn new(base: usize, size: usize) -> Self {
         Self {
             base,
             size,
-            claim_complete: [0u32; PLIC_MAX_CONTEXT],
+            contexts: Vec::new(),
+            mask: Mutex::new(PlicMask {
+                masked: [0; PLIC_MAX_IRQ / 32 + 1],
+                saved_priority: [0; PLIC_MAX_IRQ + 1],
+            }),
+        }
+    }
+    /// context of `hart` wired to local interrupt `irq`
+    pub fn context_of(&self, hart: usize, irq: u32) -> Option<usize> {
+        if self.contexts.is_empty() {
+            // sifive plic: context0=>cpu0,M mode,context1=>cpu0,S mode...
+            return match irq {
+                IRQ_M_EXT => Some(2 * hart),
+                IRQ_S_EXT => Some(2 * hart + 1),
+                _ => None,
+            };
         }
+        self.contexts.iter().position(|&c| c == (hart, irq))
+    }
+    /// S-mode external interrupt context of `hart`
+    pub fn smode_context(&self, hart: usize) -> usize {
+        self.context_of(hart, IRQ_S_EXT).unwrap()
     }
+    /// hart of `context`
+    pub fn hart_of(&self, context: usize) -> usize {
+        match self.contexts.get(context) {
+            Some(&(hart, _)) => hart,
+            None => context / 2,
+        }
+    }
+    /// set the priority of `irq_id`, kept aside until unmask if the source is masked
     pub fn set_priority(&self, irq_id: usize, priority: u32) {
+        let mut mask = self.mask.lock();
+        if test_bit(&mask.masked, irq_id) {
+            mask.saved_priority[irq_id] = priority;
+            return;
+        }
+        self.write_priority(irq_id, priority);
+    }
+    fn read_priority(&self, irq_id: usize) -> u32 {
+        let addr = self.base + PLIC_PRIORITY_BASE + irq_id * 4;
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
+    }
+    fn write_priority(&self, irq_id: usize, priority: u32) {
         let addr = self.base + PLIC_PRIORITY_BASE + irq_id * 4;
         unsafe {
             core::ptr::write_volatile(addr as *mut u32, priority);
         }
     }
+    /// mask `irq_id` on all contexts with priority 0
+    pub fn mask(&self, irq_id: usize) {
+        let mut mask = self.mask.lock();
+        if test_bit(&mask.masked, irq_id) {
+            return;
+        }
+        mask.saved_priority[irq_id] = self.read_priority(irq_id);
+        set_bit(&mut mask.masked, irq_id);
+        self.write_priority(irq_id, 0);
+    }
+    pub fn unmask(&self, irq_id: usize) {
+        let mut mask = self.mask.lock();
+        if !test_bit(&mask.masked, irq_id) {
+            return;
+        }
+        clear_bit(&mut mask.masked, irq_id);
+        self.write_priority(irq_id, mask.saved_priority[irq_id]);
+    }
+    pub fn read_pending(&self, irq_base: usize) -> u32 {
+        let addr = self.base + PLIC_PENDING_BASE + irq_base;
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
+    }
     pub fn read_enable(&self, context: usize, irq_base: usize) -> u32 {
         let addr = self.base + PLIC_ENABLE_BASE + context * 0x80 + irq_base;
         unsafe { core::ptr::read_volatile(addr as *const u32) }
@@ -90,151 +171,658 @@ impl Plic {
             core::ptr::write_volatile(addr as *mut u32, value);
         }
     }
+    pub fn is_pending(&self, irq_id: usize) -> bool {
+        self.read_pending(irq_id / 32 * 4) & (1 << (irq_id % 32)) != 0
+    }
+    pub fn set_enable_bit(&self, context: usize, irq_id: usize, enable: bool) {
+        let irq_base = irq_id / 32 * 4;
+        let value = self.read_enable(context, irq_base);
+        let bit = 1 << (irq_id % 32);
+        self.set_enable(
+            context,
+            irq_base,
+            if enable { value | bit } else { value & !bit },
+        );
+    }
     pub fn set_threshold(&self, context: usize, value: u32) {
         let addr = self.base + PLIC_GLOBAL_SIZE + context * 0x1000;
         unsafe {
             core::ptr::write_volatile(addr as *mut u32, value);
         }
     }
-    ///TODO:move to vplic
-    pub fn emul_claim(&self, context: usize) -> u32 {
-        self.claim_complete[context]
+    /// claim the highest priority pending irq of `context`, 0 if none
+    pub fn claim(&self, context: usize) -> u32 {
+        let addr = self.base + PLIC_GLOBAL_SIZE + 0x1000 * context + 0x4;
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
     }
-    pub fn emul_complete(&mut self, context: usize, irq_id: u32) {
+    pub fn complete(&self, context: usize, irq_id: u32) {
         let addr = self.base + PLIC_GLOBAL_SIZE + 0x1000 * context + 0x4;
         unsafe {
-            core::ptr::write_volatile(addr as *mut u32, irq_id as u32);
+            core::ptr::write_volatile(addr as *mut u32, irq_id);
         }
+    }
+    /// complete the irqs of `in_service` claimed on `context` and disable all sources of it
+    pub fn reset_context(&self, context: usize, in_service: &IrqSet) {
+        in_service.iter().for_each(|irq_id| {
+            in_service.remove(irq_id);
+            self.complete(context, irq_id as u32);
+        });
+        for irq_base in (0..0x80).step_by(4) {
+            self.set_enable(context, irq_base, 0);
+        }
+    }
+}
 
-        self.claim_complete[context] = 0;
-        unsafe {
-            hvip::clear_vseip();
+/// virtual PLIC of a zone, the guest state is kept in software and only the sources
+/// owned by the zone are synced to the host PLIC
+pub struct VirtPlic {
+    /// number of interrupt sources of the guest PLIC (riscv,ndev)
+    ndev: usize,
+    /// number of virtual contexts, M and S mode of each vcpu
+    contexts: usize,
+    /// host context backing each virtual context, None for the M-mode ones
+    context_map: Vec<Option<usize>>,
+    /// guest hart of each S-mode virtual context
+    context_harts: Vec<Option<usize>>,
+    /// bitmap of the sources owned by the zone
+    owned: Vec<u32>,
+    /// bitmap of the owned sources raised in software by `inject_virq`, they never
+    /// reach the host PLIC
+    virt: Vec<u32>,
+    /// virtual sources waiting for a claim, and claimed but not completed yet
+    virt_pending: Vec<u32>,
+    virt_claimed: Vec<u32>,
+    /// (guest irq, host irq) pairs of the remapped sources, the others keep their number
+    irq_map: Vec<(u32, u32)>,
+    priority: Vec<u32>,
+    /// enable words of each virtual context
+    enable: Vec<u32>,
+    threshold: Vec<u32>,
+}
+impl VirtPlic {
+    pub fn new(
+        ndev: usize,
+        context_map: Vec<Option<usize>>,
+        context_harts: Vec<Option<usize>>,
+    ) -> Self {
+        let words = ndev / 32 + 1;
+        let contexts = context_map.len();
+        Self {
+            ndev,
+            contexts,
+            context_map,
+            context_harts,
+            owned: vec![0; words],
+            virt: vec![0; words],
+            virt_pending: vec![0; words],
+            virt_claimed: vec![0; words],
+            irq_map: Vec::new(),
+            priority: vec![0; ndev + 1],
+            enable: vec![0; words * contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+    /// build the vplic of a zone from the guest device tree: sources listed in the
+    /// `interrupts` of its devices and in pci `interrupt-map`, plus `extra_irqs`.
+    /// The contexts follow the guest plic interrupts-extended, `phart_of` gives the
+    /// physical hart of a guest hart id. Guest sources are remapped to host ones by the
+    /// `hvisor,irq-map` property of the guest plic, <guest host ...>, then by `irq_map`.
+    pub fn from_fdt(
+        fdt: &fdt::Fdt,
+        extra_irqs: &[u32],
+        irq_map: &[(u32, u32)],
+        phart_of: impl Fn(usize) -> Option<usize>,
+    ) -> Self {
+        let plic = fdt.find_node("/soc/plic").unwrap();
+        let ndev = plic
+            .property("riscv,ndev")
+            .and_then(|ndev| ndev.as_usize())
+            .unwrap_or(PLIC_MAX_IRQ);
+        let contexts = plic_contexts(fdt, &plic);
+        let host_plic = host_plic();
+        let context_map = contexts
+            .iter()
+            .map(|&(vhart, irq)| match (phart_of(vhart), irq) {
+                (Some(phart), IRQ_S_EXT) => host_plic.context_of(phart, irq),
+                _ => None,
+            })
+            .collect();
+        let context_harts = contexts
+            .iter()
+            .map(|&(vhart, irq)| (irq == IRQ_S_EXT).then_some(vhart))
+            .collect();
+        let mut vplic = Self::new(ndev, context_map, context_harts);
+        // #interrupt-cells of the plic is 1
+        fdt_irqs(fdt, 1)
+            .into_iter()
+            .chain(extra_irqs.iter().copied())
+            .for_each(|irq| vplic.add_source(irq));
+        let fdt_map: Vec<u32> = plic
+            .property("hvisor,irq-map")
+            .map(|p| be32_cells(p.value).collect())
+            .unwrap_or_default();
+        fdt_map
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .chain(irq_map.iter().copied())
+            .for_each(|(guest, host)| vplic.remap_source(guest, host));
+        vplic
+    }
+    pub fn add_source(&mut self, irq: u32) {
+        let irq = irq as usize;
+        if irq == 0 || irq > self.ndev {
+            warn!("irq {} out of the vplic sources", irq);
+            return;
+        }
+        self.owned[irq / 32] |= 1 << (irq % 32);
+    }
+    /// route guest source `guest` to host source `host`, a later entry for the same guest
+    /// source replaces the earlier one
+    pub fn remap_source(&mut self, guest: u32, host: u32) {
+        if !self.owns(guest as usize) || host == 0 || host as usize > PLIC_MAX_IRQ {
+            warn!("ignore irq remap {} -> {}", guest, host);
+            return;
+        }
+        self.irq_map.retain(|&(g, _)| g != guest);
+        if self.irq_map.iter().any(|&(_, h)| h == host)
+            || host != guest
+                && self.owns(host as usize)
+                && self.host_irq(host as usize) == host as usize
+        {
+            warn!(
+                "host irq {} already used by the zone, ignore remap of {}",
+                host, guest
+            );
+            return;
+        }
+        debug!("remap guest irq {} -> host irq {}", guest, host);
+        self.irq_map.push((guest, host));
+    }
+    /// host source backing guest source `irq`
+    pub fn host_irq(&self, irq: usize) -> usize {
+        self.irq_map
+            .iter()
+            .find(|&&(guest, _)| guest as usize == irq)
+            .map_or(irq, |&(_, host)| host as usize)
+    }
+    /// guest source of host source `irq`, 0 if the zone does not see it
+    pub fn guest_irq(&self, irq: usize) -> usize {
+        match self.irq_map.iter().find(|&&(_, host)| host as usize == irq) {
+            Some(&(guest, _)) => guest as usize,
+            // a remapped guest number hides the host source of the same number
+            None if self.host_irq(irq) != irq => 0,
+            None => irq,
+        }
+    }
+    /// host sources of the zone, the virtual ones have none
+    pub fn host_irqs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.owned_irqs()
+            .filter(move |&irq| !self.is_virtual(irq))
+            .map(move |irq| self.host_irq(irq))
+    }
+    /// host sources of enable word `word` with the enable bit the guest gives them
+    fn host_enables(&self, word: usize, value: u32) -> impl Iterator<Item = (usize, bool)> + '_ {
+        (word * 32..word * 32 + 32)
+            .filter(move |&irq| self.owns(irq) && !self.is_virtual(irq))
+            .map(move |irq| (self.host_irq(irq), value & (1 << (irq % 32)) != 0))
+    }
+    /// make an owned source virtual, raised only by `inject_virq`
+    pub fn add_virtual_source(&mut self, irq: u32) {
+        self.add_source(irq);
+        if self.owns(irq as usize) {
+            set_bit(&mut self.virt, irq as usize);
+        }
+    }
+    pub fn is_virtual(&self, irq: usize) -> bool {
+        irq <= self.ndev && test_bit(&self.virt, irq)
+    }
+    /// host context backing `vcontext`, None if it has no hardware context
+    pub fn host_context(&self, vcontext: usize) -> Option<usize> {
+        self.context_map.get(vcontext).copied().flatten()
+    }
+    /// S-mode virtual context of guest hart `vhart`
+    pub fn smode_vcontext(&self, vhart: usize) -> Option<usize> {
+        self.context_harts.iter().position(|&h| h == Some(vhart))
+    }
+    pub fn owns(&self, irq: usize) -> bool {
+        irq <= self.ndev && self.owned[irq / 32] & (1 << (irq % 32)) != 0
+    }
+    fn owned_word(&self, word: usize) -> u32 {
+        self.owned.get(word).copied().unwrap_or(0)
+    }
+    pub fn owned_irqs(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..=self.ndev).filter(move |&irq| self.owns(irq))
+    }
+    /// virtual source `irq` is enabled on `vcontext` above its threshold
+    fn virq_enabled(&self, vcontext: usize, irq: usize) -> bool {
+        let words = self.owned.len();
+        test_bit(&self.enable[vcontext * words..(vcontext + 1) * words], irq)
+            && self.priority[irq] > self.threshold[vcontext]
+    }
+    /// highest priority virtual source `vcontext` may claim, the lowest id on ties
+    fn best_virq(&self, vcontext: usize) -> Option<usize> {
+        (1..=self.ndev)
+            .filter(|&irq| test_bit(&self.virt_pending, irq) && !test_bit(&self.virt_claimed, irq))
+            .filter(|&irq| self.virq_enabled(vcontext, irq))
+            .max_by_key(|&irq| (self.priority[irq], usize::MAX - irq))
+    }
+    /// mark virtual source `irq` pending, returns the guest harts that may claim it
+    pub fn set_virq_pending(&mut self, irq: usize) -> Vec<usize> {
+        set_bit(&mut self.virt_pending, irq);
+        if test_bit(&self.virt_claimed, irq) {
+            // delivered again once the guest completes it
+            return Vec::new();
+        }
+        (0..self.contexts)
+            .filter(|&vcontext| self.virq_enabled(vcontext, irq))
+            .filter_map(|vcontext| self.context_harts[vcontext])
+            .collect()
+    }
+    /// guest hart of `vcontext` if it may claim one of the pending virtual sources
+    pub fn virq_target(&self, vcontext: usize) -> Option<usize> {
+        self.best_virq(vcontext)
+            .and_then(|_| self.context_harts.get(vcontext).copied().flatten())
+    }
+    /// take the host irq of highest guest priority from the irqs claimed on this hart,
+    /// returns its guest source
+    pub fn claim_host_irq(&self, pending: &IrqSet) -> Option<usize> {
+        let irq = pending
+            .iter()
+            .max_by_key(|&irq| (self.priority[self.guest_irq(irq)], usize::MAX - irq))?;
+        pending.remove(irq);
+        Some(self.guest_irq(irq))
+    }
+    pub fn claim_virq(&mut self, vcontext: usize) -> Option<usize> {
+        let irq = self.best_virq(vcontext)?;
+        clear_bit(&mut self.virt_pending, irq);
+        set_bit(&mut self.virt_claimed, irq);
+        Some(irq)
+    }
+    pub fn complete_virq(&mut self, irq: usize) {
+        clear_bit(&mut self.virt_claimed, irq);
+    }
+    pub fn virq_deliverable(&self, vcontext: usize) -> bool {
+        self.best_virq(vcontext).is_some()
+    }
+    /// forget the guest state, used when the zone is rebooted
+    pub fn reset(&mut self) {
+        self.priority.fill(0);
+        self.enable.fill(0);
+        self.threshold.fill(0);
+        self.virt_pending.fill(0);
+        self.virt_claimed.fill(0);
+    }
+}
+fn test_bit(bits: &[u32], irq: usize) -> bool {
+    bits.get(irq / 32)
+        .map_or(false, |word| word & (1 << (irq % 32)) != 0)
+}
+fn set_bit(bits: &mut [u32], irq: usize) {
+    bits[irq / 32] |= 1 << (irq % 32);
+}
+fn clear_bit(bits: &mut [u32], irq: usize) {
+    bits[irq / 32] &= !(1 << (irq % 32));
+}
+/// raise virtual source `virq` of `zone` for an emulated device. The vcpus that may claim
+/// it are notified, by IPI when they run on another hart.
+pub fn inject_virq(zone: &Zone, virq: u32) -> HvResult {
+    let mut vplic = zone.vplic.lock();
+    if !vplic.is_virtual(virq as usize) {
+        return hv_result_err!(
+            EINVAL,
+            format!("irq {} is not a virtual source of zone {}", virq, zone.vmid)
+        );
+    }
+    let vharts = vplic.set_virq_pending(virq as usize);
+    drop(vplic);
+    trace!(
+        "zone {} inject virq {} to harts {:?}",
+        zone.vmid,
+        virq,
+        vharts
+    );
+    vharts.into_iter().for_each(|vhart| kick_vhart(zone, vhart));
+    Ok(())
+}
+/// make guest hart `vhart` of `zone` look at its virtual sources again
+fn kick_vhart(zone: &Zone, vhart: usize) {
+    let phart = match zone.phart_of(vhart) {
+        Some(phart) => phart,
+        None => return,
+    };
+    if phart == this_cpu_id() {
+        unsafe { hvip::set_vseip() };
+        return;
+    }
+    get_cpu_data(phart)
+        .arch_cpu
+        .virq_kick
+        .store(true, Ordering::Release);
+    sbi_rt::send_ipi(1 << phart, 0);
+}
+/// raise or drop the guest external interrupt of the current vcpu, from the irq claimed on
+/// its host context and the virtual sources it may claim
+fn sync_vseip(current_cpu: &ArchCpu, vplic: &VirtPlic) {
+    let vcontext = match vplic.smode_vcontext(current_cpu.vhartid) {
+        Some(vcontext) => vcontext,
+        None => return,
+    };
+    if !current_cpu.irq_pending.is_empty() || vplic.virq_deliverable(vcontext) {
+        unsafe { hvip::set_vseip() };
+    } else {
+        unsafe { hvip::clear_vseip() };
+    }
+}
+pub const PLIC_MAX_IRQ: usize = 1023;
+/// interrupt sources used by the devices of a device tree: the `interrupts` of every node,
+/// with `irq_cells` cells per interrupt, and the parent interrupts of pci `interrupt-map`
+pub fn fdt_irqs(fdt: &fdt::Fdt, irq_cells: usize) -> Vec<u32> {
+    let mut irqs = Vec::new();
+    for node in fdt.all_nodes() {
+        if let Some(interrupts) = node.property("interrupts") {
+            let cells: Vec<u32> = be32_cells(interrupts.value).collect();
+            irqs.extend(cells.iter().step_by(irq_cells.max(1)));
+        }
+        if let Some(map) = node.property("interrupt-map") {
+            interrupt_map_irqs(fdt, &node, map.value, &mut irqs);
         }
     }
+    irqs
+}
+/// each interrupt-map entry is: child unit address, child irq, parent phandle,
+/// parent unit address, parent irq
+fn interrupt_map_irqs(fdt: &fdt::Fdt, node: &fdt::node::FdtNode, map: &[u8], irqs: &mut Vec<u32>) {
+    let child_cells = fdt_cells(node, "#address-cells") + fdt_cells(node, "#interrupt-cells");
+    let cells: Vec<u32> = be32_cells(map).collect();
+    let mut i = 0;
+    while i + child_cells < cells.len() {
+        let parent = match fdt.find_phandle(cells[i + child_cells]) {
+            Some(parent) => parent,
+            None => {
+                warn!("interrupt-map of {} has an unknown parent", node.name);
+                return;
+            }
+        };
+        let irq_index = i + child_cells + 1 + fdt_cells(&parent, "#address-cells");
+        if let Some(&irq) = cells.get(irq_index) {
+            irqs.push(irq);
+        }
+        i = irq_index + fdt_cells(&parent, "#interrupt-cells").max(1);
+    }
+}
+/// value of a #xxx-cells property, 0 if absent
+fn fdt_cells(node: &fdt::node::FdtNode, name: &str) -> usize {
+    node.property(name).and_then(|p| p.as_usize()).unwrap_or(0)
+}
+pub fn be32_cells(value: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    value
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+}
+/// hart id and local interrupt of each context of a plic node, in the order of its
+/// interrupts-extended, e.g. <&cpu0_intc 11 &cpu0_intc 9> gives [(0, 11), (0, 9)]
+pub fn plic_contexts(fdt: &fdt::Fdt, plic: &fdt::node::FdtNode) -> Vec<(usize, u32)> {
+    // phandle of the interrupt controller of each hart
+    let harts: Vec<(u32, usize)> = fdt
+        .find_all_nodes("/cpus/cpu")
+        .filter_map(|cpu| {
+            let hart = cpu.property("reg")?.as_usize()?;
+            let intc = cpu
+                .children()
+                .find_map(|child| child.property("phandle")?.as_usize())?;
+            Some((intc as u32, hart))
+        })
+        .collect();
+    let cells: Vec<u32> = plic
+        .property("interrupts-extended")
+        .map(|p| be32_cells(p.value).collect())
+        .unwrap_or_default();
+    // the hart interrupt controllers have one interrupt cell
+    cells
+        .chunks_exact(2)
+        .map(|entry| {
+            let hart = harts
+                .iter()
+                .find(|&&(intc, _)| intc == entry[0])
+                .map_or(usize::MAX, |&(_, hart)| hart);
+            (hart, entry[1])
+        })
+        .collect()
 }
 
-pub fn vplic_global_emul_handler(
+/// vPLIC of the zone running on the current hart, registered in the zone MMIO regions at
+/// the guest plic address. Only 32-bit accesses are allowed.
+pub struct VirtPlicDevice;
+impl MmioDevice for VirtPlicDevice {
+    fn read(
+        &self,
+        current_cpu: &mut ArchCpu,
+        zone: &Zone,
+        offset: usize,
+        width: usize,
+    ) -> HvResult<usize> {
+        if width != 4 {
+            return hv_result_err!(EINVAL, format!("PLIC read of width {}", width));
+        }
+        Ok(vplic_access(current_cpu, zone, offset, None) as usize)
+    }
+    fn write(
+        &self,
+        current_cpu: &mut ArchCpu,
+        zone: &Zone,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> HvResult {
+        if width != 4 {
+            return hv_result_err!(EINVAL, format!("PLIC write of width {}", width));
+        }
+        vplic_access(current_cpu, zone, offset, Some(value as u32));
+        Ok(())
+    }
+}
+/// emulate a guest access to its PLIC, returns the value of a read
+fn vplic_access(current_cpu: &mut ArchCpu, zone: &Zone, offset: usize, write: Option<u32>) -> u32 {
+    let mut vplic = zone.vplic.lock();
+    if offset < PLIC_GLOBAL_SIZE {
+        vplic_global_access(current_cpu, zone, &mut vplic, offset, write)
+    } else if offset < PLIC_TOTAL_SIZE {
+        vplic_hart_access(current_cpu, zone, &mut vplic, offset, write)
+    } else {
+        warn!(
+            "CPU{} access to absent PLIC register {:#x}",
+            current_cpu.hartid, offset
+        );
+        0
+    }
+}
+fn vplic_global_access(
     current_cpu: &mut ArchCpu,
-    addr: GuestPhysAddr,
-    inst: Instruction,
-) {
-    //TODO:check irq id for vm
+    zone: &Zone,
+    vplic: &mut VirtPlic,
+    offset: usize,
+    write: Option<u32>,
+) -> u32 {
     let host_plic = host_plic();
-    let offset = addr.wrapping_sub(host_plic.read().base);
-    // priority/pending/enable
-    if offset >= PLIC_PRIORITY_BASE && offset < PLIC_ENABLE_BASE {
-        // priority/pending
-        match inst {
-            Instruction::Sw(i) => {
-                // guest write irq priority
-                //TODO:check irq id for vm
-                let irq_id = offset / 4;
-                let value = current_cpu.x[i.rs2() as usize] as u32;
-                host_plic.write().set_priority(irq_id, value);
-                info!(
-                    "PLIC set priority write addr@{:#x} irq id {} valuse{:#x}",
-                    addr, irq_id, value
+    if offset >= PLIC_PRIORITY_BASE && offset < PLIC_PENDING_BASE {
+        // priority
+        let irq_id = offset / 4;
+        match write {
+            Some(value) => {
+                if vplic.owns(irq_id) {
+                    vplic.priority[irq_id] = value;
+                    if !vplic.is_virtual(irq_id) {
+                        host_plic.set_priority(vplic.host_irq(irq_id), value);
+                    }
+                    sync_vseip(current_cpu, vplic);
+                    info!(
+                        "PLIC set priority write offset@{:#x} irq id {} valuse{:#x}",
+                        offset, irq_id, value
+                    );
+                } else {
+                    warn!(
+                        "CPU{} ignore priority write of irq {} not owned by zone {}",
+                        current_cpu.hartid, irq_id, zone.vmid
+                    );
+                }
+                0
+            }
+            None if vplic.owns(irq_id) => vplic.priority[irq_id],
+            None => 0,
+        }
+    } else if offset >= PLIC_PENDING_BASE && offset < PLIC_ENABLE_BASE {
+        // pending, read only
+        let irq_base = offset - PLIC_PENDING_BASE;
+        match write {
+            None => (irq_base * 8..irq_base * 8 + 32)
+                .filter(|&irq| vplic.owns(irq) && !vplic.is_virtual(irq))
+                .filter(|&irq| host_plic.is_pending(vplic.host_irq(irq)))
+                .fold(0u32, |acc, irq| acc | 1 << (irq % 32)),
+            Some(_) => {
+                warn!(
+                    "CPU{} ignore write to PLIC pending {:#x}",
+                    current_cpu.hartid, offset
                 );
+                0
             }
-            _ => panic!("Unexpected instruction {:?}", inst),
         }
-    } else if offset >= PLIC_ENABLE_BASE && offset < PLIC_GLOBAL_SIZE {
+    } else {
         //enable
-        match inst {
-            Instruction::Lw(i) => {
+        let vcontext = (offset - PLIC_ENABLE_BASE) / 0x80;
+        let irq_base = (offset - PLIC_ENABLE_BASE) % 0x80;
+        let word = irq_base / 4;
+        let words = vplic.owned.len();
+        if vcontext >= vplic.contexts || word >= words {
+            warn!(
+                "CPU{} access to absent PLIC enable {:#x}",
+                current_cpu.hartid, offset
+            );
+            return 0;
+        }
+        let context = vplic.host_context(vcontext);
+        match write {
+            None => {
                 // guest read
-                let vcontext = (offset - 0x002000) / 0x80;
-                let context = vcontext + current_cpu.first_cpu * 2;
-                let irq_base = (offset - 0x002000) % 0x80;
-                let value = host_plic.read().read_enable(context, irq_base);
-                current_cpu.x[i.rd() as usize] = value as usize;
+                let value = vplic.enable[vcontext * words + word];
                 info!(
-                    "PLIC set enable read addr@{:#x} -> context {}=>{}  irq_base {}~{} value {:#x}",
-                    addr,
+                    "PLIC set enable read offset@{:#x} -> context {}=>{:?}  irq_base {}~{} value {:#x}",
+                    offset,
                     vcontext,
                     context,
                     irq_base * 8,
                     irq_base * 8 + 31,
                     value
                 );
+                value
             }
-            Instruction::Sw(i) => {
-                // guest write irq enable
-                let vcontext = (offset - 0x002000) / 0x80;
-                let context = vcontext + current_cpu.first_cpu * 2;
-                let irq_base = (offset - 0x002000) % 0x80;
-                let value = current_cpu.x[i.rs2() as usize] as u32;
-                host_plic.write().set_enable(context, irq_base, value);
-
+            Some(value) => {
+                // guest write irq enable, bits of foreign sources are dropped
+                let value = value & vplic.owned_word(word);
+                vplic.enable[vcontext * words + word] = value;
+                if let Some(context) = context {
+                    let hart = host_plic.hart_of(context);
+                    vplic
+                        .host_enables(word, value)
+                        .for_each(|(irq, enable)| irqchip().enable(hart, irq as u32, enable));
+                }
+                // a pending virtual source may now be routed to another vcpu
+                match vplic.virq_target(vcontext) {
+                    Some(vhart) if vhart != current_cpu.vhartid => kick_vhart(zone, vhart),
+                    _ => sync_vseip(current_cpu, vplic),
+                }
                 info!(
-                    "PLIC set enable write addr@{:#x} -> context{}=>{}  irq_base {}~{} value {:#x}",
-                    addr,
+                    "PLIC set enable write offset@{:#x} -> context{}=>{:?}  irq_base {}~{} value {:#x}",
+                    offset,
                     vcontext,
                     context,
                     irq_base * 8,
                     irq_base * 8 + 31,
                     value
                 );
+                0
             }
-            _ => panic!("Unexpected instruction {:?}", inst),
         }
-    } else {
-        panic!("Invalid address: {:#x}", addr);
     }
 }
-pub fn vplic_hart_emul_handler(current_cpu: &mut ArchCpu, addr: GuestPhysAddr, inst: Instruction) {
-    trace!("handle PLIC access addr@{:#x}", addr);
+fn vplic_hart_access(
+    current_cpu: &mut ArchCpu,
+    zone: &Zone,
+    vplic: &mut VirtPlic,
+    offset: usize,
+    write: Option<u32>,
+) -> u32 {
+    trace!("handle PLIC access offset@{:#x}", offset);
     let host_plic = host_plic();
-    let offset = addr.wrapping_sub(host_plic.read().base);
     // threshold/claim/complete
-    if offset >= PLIC_GLOBAL_SIZE && offset < PLIC_TOTAL_SIZE {
-        let vcontext = (offset - PLIC_GLOBAL_SIZE) / 0x1000;
-        let context = vcontext + current_cpu.first_cpu * 2;
-        let index = ((offset - PLIC_GLOBAL_SIZE) & 0xfff);
-        if index == 0 {
-            // threshold
-            match inst {
-                Instruction::Sw(i) => {
-                    // guest write threshold register to plic core
-                    let value = current_cpu.x[i.rs2() as usize] as u32;
-                    host_plic.write().set_threshold(context, value);
-                    info!(
-                        "PLIC set threshold write addr@{:#x} context{} -> {:#x}",
-                        addr, context, value
-                    );
-                }
-                _ => panic!("Unexpected instruction threshold {:?}", inst),
+    let vcontext = (offset - PLIC_GLOBAL_SIZE) / 0x1000;
+    let index = (offset - PLIC_GLOBAL_SIZE) & 0xfff;
+    if vcontext >= vplic.contexts || index > 0x4 {
+        warn!(
+            "CPU{} access to absent PLIC context register {:#x}",
+            current_cpu.hartid, offset
+        );
+        return 0;
+    }
+    let context = vplic.host_context(vcontext);
+    match (index, write) {
+        // threshold
+        (0, Some(value)) => {
+            // guest write threshold register to plic core
+            vplic.threshold[vcontext] = value;
+            if let Some(context) = context {
+                host_plic.set_threshold(context, value);
             }
-        } else if index == 0x4 {
-            // claim/complete
-            // htracking!("claim/complete");
-            match inst {
-                Instruction::Lw(i) => {
-                    // guest read claim from plic core
-                    current_cpu.x[i.rd() as usize] = host_plic.read().emul_claim(context) as usize;
-                    debug!(
-                        "PLIC claim read addr@{:#x} context{} -> {:#x}",
-                        addr,
-                        context,
-                        host_plic.read().claim_complete[context]
-                    );
-                }
-                Instruction::Sw(i) => {
-                    // guest write complete to plic core
-                    let value = current_cpu.x[i.rs2() as usize] as u32;
-                    host_plic.write().emul_complete(context, value);
-                    // todo: guest pa -> host pa
-                    debug!(
-                        "PLIC complete write addr@:{:#x} context {} -> {:#x}",
-                        addr, context, value
-                    );
-                }
-                _ => panic!("Unexpected instruction claim/complete {:?}", inst),
+            sync_vseip(current_cpu, vplic);
+            info!(
+                "PLIC set threshold write offset@{:#x} context{:?} -> {:#x}",
+                offset, context, value
+            );
+            0
+        }
+        (0, None) => vplic.threshold[vcontext],
+        // claim/complete
+        (0x4, None) => {
+            // guest read claim from plic core, the host only claims sources enabled by the zone
+            // irqs claimed on this hart go first, then the virtual sources
+            let own_context = vplic.smode_vcontext(current_cpu.vhartid) == Some(vcontext);
+            let host_irq = match (context, own_context) {
+                (Some(_), true) => vplic.claim_host_irq(&current_cpu.irq_pending),
+                _ => None,
+            };
+            let irq = host_irq.or_else(|| vplic.claim_virq(vcontext)).unwrap_or(0);
+            sync_vseip(current_cpu, vplic);
+            debug!(
+                "PLIC claim read offset@{:#x} context{:?} -> {:#x}",
+                offset, context, irq
+            );
+            irq as u32
+        }
+        (0x4, Some(value)) => {
+            // guest write complete to plic core
+            if vplic.is_virtual(value as usize) {
+                vplic.complete_virq(value as usize);
+                sync_vseip(current_cpu, vplic);
+            } else if let (Some(context), true) = (context, vplic.owns(value as usize)) {
+                let host_irq = vplic.host_irq(value as usize) as u32;
+                let hart = host_plic.hart_of(context);
+                irqchip().complete(hart, host_irq);
+                get_cpu_data(hart)
+                    .arch_cpu
+                    .irq_in_service
+                    .remove(host_irq as usize);
+                sync_vseip(current_cpu, vplic);
+            } else {
+                warn!(
+                    "CPU{} ignore complete of irq {} not owned by zone {}",
+                    current_cpu.hartid, value, zone.vmid
+                );
             }
-        } else {
-            panic!("Invalid address: {:#x}", addr);
+            debug!(
+                "PLIC complete write offset@:{:#x} context {:?} -> {:#x}",
+                offset, context, value
+            );
+            0
         }
-    } else {
-        panic!("Invalid address: {:#x}", addr);
+        _ => 0,
     }
 }
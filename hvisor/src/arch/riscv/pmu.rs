@@ -0,0 +1,213 @@
+//! Per-zone virtual PMU behind the SBI PMU extension.
+//!
+//! A zone owns a subset of the SBI counters of its harts, the guest sees them as
+//! counters 0..n in the order of their physical counter index.
+use super::csr::*;
+use super::sbi::{sbi_call_5, SBI_EID, SBI_SUCCESS};
+
+pub const MAX_VPMU_COUNTERS: usize = 32;
+pub mod SBI_PMU_FID {
+    pub const NUM_COUNTERS: usize = 0;
+    pub const COUNTER_GET_INFO: usize = 1;
+    pub const COUNTER_CONFIG_MATCHING: usize = 2;
+    pub const COUNTER_START: usize = 3;
+    pub const COUNTER_STOP: usize = 4;
+    pub const COUNTER_FW_READ: usize = 5;
+    pub const COUNTER_FW_READ_HI: usize = 6;
+    pub const SNAPSHOT_SET_SHMEM: usize = 7;
+}
+pub const SBI_PMU_CFG_FLAG_AUTO_START: usize = 1 << 2;
+pub const SBI_PMU_START_FLAG_SET_INIT_VALUE: usize = 1 << 0;
+pub const SBI_PMU_STOP_FLAG_RESET: usize = 1 << 0;
+/// counter_get_info: set for firmware counters, hardware counters carry their csr in bits [11:0]
+const SBI_PMU_INFO_TYPE_FW: usize = 1 << 63;
+pub const CSR_CYCLE: usize = 0xC00;
+
+#[derive(Debug)]
+pub struct Vpmu {
+    /// physical counter index of each virtual counter
+    counters: [usize; MAX_VPMU_COUNTERS],
+    /// counter_get_info of each virtual counter
+    info: [usize; MAX_VPMU_COUNTERS],
+    num: usize,
+    /// virtual counters started by the guest
+    started: usize,
+    /// values of the started counters while the vcpu is parked
+    saved: [u64; MAX_VPMU_COUNTERS],
+}
+
+impl Vpmu {
+    pub const fn empty() -> Self {
+        Self {
+            counters: [0; MAX_VPMU_COUNTERS],
+            info: [0; MAX_VPMU_COUNTERS],
+            num: 0,
+            started: 0,
+            saved: [0; MAX_VPMU_COUNTERS],
+        }
+    }
+    /// own the counters of `counter_mask` that exist on this platform
+    pub fn new(counter_mask: usize) -> Self {
+        let mut vpmu = Self::empty();
+        for pidx in 0..usize::BITS as usize {
+            if counter_mask & (1 << pidx) == 0 || vpmu.num == MAX_VPMU_COUNTERS {
+                continue;
+            }
+            let ret = sbi_call_5(
+                SBI_EID::PMU,
+                SBI_PMU_FID::COUNTER_GET_INFO,
+                pidx,
+                0,
+                0,
+                0,
+                0,
+            );
+            if ret.error != SBI_SUCCESS {
+                warn!("PMU counter {} not present, skip it", pidx);
+                continue;
+            }
+            vpmu.counters[vpmu.num] = pidx;
+            vpmu.info[vpmu.num] = ret.value as usize;
+            vpmu.num += 1;
+        }
+        vpmu
+    }
+    pub fn num_counters(&self) -> usize {
+        self.num
+    }
+    pub fn counter_info(&self, vidx: usize) -> Option<usize> {
+        (vidx < self.num).then(|| self.info[vidx])
+    }
+    pub fn phys_idx(&self, vidx: usize) -> Option<usize> {
+        (vidx < self.num).then(|| self.counters[vidx])
+    }
+    pub fn virt_idx(&self, pidx: usize) -> Option<usize> {
+        self.counters[..self.num].iter().position(|&p| p == pidx)
+    }
+    /// hcounteren bits letting the guest read its hardware counters
+    pub fn hcounteren(&self) -> usize {
+        self.info[..self.num]
+            .iter()
+            .filter(|&&info| info & SBI_PMU_INFO_TYPE_FW == 0)
+            .filter_map(|&info| (info & 0xfff).checked_sub(CSR_CYCLE))
+            .filter(|&bit| bit < usize::BITS as usize)
+            .fold(0, |acc, bit| acc | 1 << bit)
+    }
+    /// value of the hardware counter at `csr` if the zone owns it
+    pub fn read_hw_counter(&self, csr: usize) -> Option<u64> {
+        self.info[..self.num]
+            .iter()
+            .any(|&info| info & SBI_PMU_INFO_TYPE_FW == 0 && info & 0xfff == csr)
+            .then(|| read_hpm_counter(csr - CSR_CYCLE))
+    }
+    /// translate a guest counter_idx_base/counter_idx_mask pair to a mask of physical counters
+    pub fn phys_mask(&self, base: usize, mask: usize) -> Option<usize> {
+        let mut phys_mask = 0;
+        for i in 0..usize::BITS as usize {
+            if mask & (1 << i) == 0 {
+                continue;
+            }
+            let pidx = base.checked_add(i).and_then(|vidx| self.phys_idx(vidx))?;
+            phys_mask |= 1 << pidx;
+        }
+        Some(phys_mask)
+    }
+    /// record the counters a successful start/stop call applied to
+    pub fn set_started(&mut self, phys_mask: usize, started: bool) {
+        for vidx in 0..self.num {
+            if phys_mask & (1 << self.counters[vidx]) == 0 {
+                continue;
+            }
+            if started {
+                self.started |= 1 << vidx;
+            } else {
+                self.started &= !(1 << vidx);
+            }
+        }
+    }
+    fn read_counter(&self, vidx: usize) -> u64 {
+        if self.info[vidx] & SBI_PMU_INFO_TYPE_FW != 0 {
+            let ret = sbi_call_5(
+                SBI_EID::PMU,
+                SBI_PMU_FID::COUNTER_FW_READ,
+                self.counters[vidx],
+                0,
+                0,
+                0,
+                0,
+            );
+            ret.value as u64
+        } else {
+            read_hpm_counter((self.info[vidx] & 0xfff) - CSR_CYCLE)
+        }
+    }
+    fn stop_counters(&self, mask: usize, flags: usize) {
+        sbi_call_5(
+            SBI_EID::PMU,
+            SBI_PMU_FID::COUNTER_STOP,
+            0,
+            mask,
+            flags,
+            0,
+            0,
+        );
+    }
+    fn phys_started_mask(&self) -> usize {
+        (0..self.num)
+            .filter(|vidx| self.started & (1 << vidx) != 0)
+            .fold(0, |acc, vidx| acc | 1 << self.counters[vidx])
+    }
+    /// stop the guest counters while the vcpu is parked and keep their values
+    pub fn save(&mut self) {
+        if self.started == 0 {
+            return;
+        }
+        self.stop_counters(self.phys_started_mask(), 0);
+        for vidx in 0..self.num {
+            if self.started & (1 << vidx) != 0 {
+                self.saved[vidx] = self.read_counter(vidx);
+            }
+        }
+    }
+    /// start the counters stopped by `save` again from their saved values
+    pub fn restore(&mut self) {
+        for vidx in 0..self.num {
+            if self.started & (1 << vidx) == 0 {
+                continue;
+            }
+            sbi_call_5(
+                SBI_EID::PMU,
+                SBI_PMU_FID::COUNTER_START,
+                self.counters[vidx],
+                1,
+                SBI_PMU_START_FLAG_SET_INIT_VALUE,
+                self.saved[vidx] as usize,
+                0,
+            );
+        }
+    }
+    /// stop and release all guest counters, the vcpu restarts from scratch
+    pub fn reset(&mut self) {
+        let all = (0..self.num).fold(0, |acc, vidx| acc | 1 << self.counters[vidx]);
+        if all != 0 {
+            self.stop_counters(all, SBI_PMU_STOP_FLAG_RESET);
+        }
+        self.started = 0;
+    }
+}
+
+/// read hpmcounter `n`, the csr number of csrr must be an immediate
+fn read_hpm_counter(n: usize) -> u64 {
+    macro_rules! read_counter {
+        ($($i:literal),*) => {
+            match n {
+                $($i => read_csr!(0xC00 + $i) as u64,)*
+                _ => 0,
+            }
+        };
+    }
+    read_counter!(
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30, 31
+    )
+}
@@ -0,0 +1,190 @@
+//! Dispatcher of the stage-2 faults of the guests: the guest page faults and the access
+//! faults of instruction fetches, loads and stores.
+//!
+//! A fault is classified by the access and a reason code, then handled in this order:
+//! - demand-allocated guest RAM, populated on its first access;
+//! - faults on a mapping that allows the access, raced with the hart populating it;
+//! - emulated MMIO regions of the zone, for loads and stores that have no mapping;
+//! - read-only mappings, that behave like ROM: stores to them are dropped;
+//! - everything else is raised in the guest as an access fault.
+use super::cpu::ArchCpu;
+use super::csr::*;
+use super::decode::decode_mmio_access;
+use super::trap::{inject_exception, ExceptionType};
+use crate::memory::{GuestPhysAddr, MemFlags};
+use crate::percpu::get_cpu_data;
+use crate::zone::Zone;
+use spin::RwLock;
+
+/// access that faulted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuestAccess {
+    Fetch,
+    Load,
+    Store,
+}
+impl GuestAccess {
+    /// stage-2 permission the access needs
+    fn flags(self) -> MemFlags {
+        match self {
+            GuestAccess::Fetch => MemFlags::EXECUTE,
+            GuestAccess::Load => MemFlags::READ,
+            GuestAccess::Store => MemFlags::WRITE,
+        }
+    }
+    /// exception the guest gets when the access can not be handled
+    fn access_fault(self) -> usize {
+        match self {
+            GuestAccess::Fetch => ExceptionType::INST_ACCESS_FAULT,
+            GuestAccess::Load => ExceptionType::LOAD_ACCESS_FAULT,
+            GuestAccess::Store => ExceptionType::STORE_ACCESS_FAULT,
+        }
+    }
+}
+/// why the access faulted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage2FaultReason {
+    /// no stage-2 mapping at the address
+    Unmapped,
+    /// mapped without the permission the access needs
+    Protection,
+    /// the host physical access itself failed (PMP/PMA)
+    AccessFault,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct Stage2Fault {
+    pub access: GuestAccess,
+    pub reason: Stage2FaultReason,
+    /// faulting guest physical address, 0 if the hardware did not report it
+    pub gpa: GuestPhysAddr,
+    /// faulting guest virtual address, as the guest sees it in stval
+    pub gva: usize,
+}
+
+/// access and reason of the fault `trap_code`, on an address that has a stage-2 mapping
+/// if `mapped`
+fn classify(trap_code: usize, mapped: bool) -> (GuestAccess, Stage2FaultReason) {
+    let access = match trap_code {
+        ExceptionType::INST_GUEST_PAGE_FAULT | ExceptionType::INST_ACCESS_FAULT => {
+            GuestAccess::Fetch
+        }
+        ExceptionType::LOAD_GUEST_PAGE_FAULT | ExceptionType::LOAD_ACCESS_FAULT => {
+            GuestAccess::Load
+        }
+        _ => GuestAccess::Store,
+    };
+    let reason = match trap_code {
+        ExceptionType::INST_ACCESS_FAULT
+        | ExceptionType::LOAD_ACCESS_FAULT
+        | ExceptionType::STORE_ACCESS_FAULT => Stage2FaultReason::AccessFault,
+        _ if mapped => Stage2FaultReason::Protection,
+        _ => Stage2FaultReason::Unmapped,
+    };
+    (access, reason)
+}
+/// handle one of the guest page fault or access fault exceptions `trap_code`
+pub fn stage2_fault_handler(current_cpu: &mut ArchCpu, trap_code: usize) {
+    let gva = read_csr!(CSR_STVAL);
+    // htval only holds bits 2 and up of the faulting guest physical address
+    let gpa: GuestPhysAddr = (read_csr!(CSR_HTVAL) << 2) | (gva & 0b11);
+    let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+    let mapping = unsafe { zone.read().gpm.page_table_query(gpa) }
+        .ok()
+        .map(|(_, flags, _)| flags);
+    let (access, reason) = classify(trap_code, mapping.is_some());
+    let fault = Stage2Fault {
+        access,
+        reason,
+        gpa,
+        gva,
+    };
+    trace!("CPU {} stage-2 fault {:#x?}", current_cpu.hartid, fault);
+    let handled = match (fault.reason, fault.access) {
+        (Stage2FaultReason::Unmapped, _) if zone.read().demand_ram && zone.read().is_ram(gpa) => {
+            match zone.write().populate_ram(gpa) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("zone RAM at {:#x} not populated: {:?}", gpa, e);
+                    false
+                }
+            }
+        }
+        (Stage2FaultReason::Protection, _)
+            if mapping.map_or(false, |flags| flags.contains(access.flags())) =>
+        {
+            // stale translation of a page populated by another hart, retry
+            zone.read().gpm.flush(Some(gpa));
+            true
+        }
+        (Stage2FaultReason::Unmapped, GuestAccess::Load | GuestAccess::Store)
+            if zone.read().mmio.find(gpa).is_some() =>
+        {
+            mmio_emulate(current_cpu, &zone, &fault)
+        }
+        (Stage2FaultReason::Protection, GuestAccess::Store) => {
+            let read_only = mapping.map_or(false, |flags| flags.contains(MemFlags::READ));
+            read_only && rom_write(current_cpu, &fault)
+        }
+        _ => false,
+    };
+    if !handled {
+        warn!(
+            "CPU {} unhandled {:?} {:?} fault at {:#x} (gva {:#x}, pc {:#x})",
+            current_cpu.hartid, fault.access, fault.reason, gpa, gva, current_cpu.sepc
+        );
+        inject_exception(current_cpu, fault.access.access_fault(), gva);
+    }
+}
+/// emulate the load or store of `fault` on the device of the zone at its address, false if
+/// it failed
+fn mmio_emulate(current_cpu: &mut ArchCpu, zone: &RwLock<Zone>, fault: &Stage2Fault) -> bool {
+    // reading the instruction may populate demand RAM, the zone must not be locked
+    let access = match decode_mmio_access(current_cpu, read_csr!(CSR_HTINST)) {
+        Ok(access) => access,
+        Err(e) => {
+            error!(
+                "CPU {} bad mmio access at {:#x}: {:?}",
+                current_cpu.hartid, fault.gpa, e
+            );
+            return false;
+        }
+    };
+    trace!("mmio access {:#x?}", access);
+    // the zone stays read-locked while the device runs
+    let zone = zone.read();
+    let (dev, offset) = match zone.mmio.find(fault.gpa) {
+        Some(found) => found,
+        None => return false,
+    };
+    let result = if access.is_write {
+        let value = access.truncate(current_cpu.x[access.reg]);
+        dev.write(current_cpu, &zone, offset, access.width, value)
+    } else {
+        dev.read(current_cpu, &zone, offset, access.width)
+            .map(|value| {
+                if access.reg != 0 {
+                    current_cpu.x[access.reg] = access.extend(value);
+                }
+            })
+    };
+    if let Err(e) = result {
+        error!("mmio access at {:#x} failed: {:?}", fault.gpa, e);
+        return false;
+    }
+    current_cpu.sepc += access.inst_len;
+    true
+}
+/// a store to a read-only mapping is dropped, like a write to ROM
+fn rom_write(current_cpu: &mut ArchCpu, fault: &Stage2Fault) -> bool {
+    match decode_mmio_access(current_cpu, read_csr!(CSR_HTINST)) {
+        Ok(access) => {
+            debug!(
+                "CPU {} ignore write to read-only {:#x}",
+                current_cpu.hartid, fault.gpa
+            );
+            current_cpu.sepc += access.inst_len;
+            true
+        }
+        Err(_) => false,
+    }
+}
@@ -162,8 +162,16 @@ impl PagingInstr for S2PTInstr {
         }
     }
 
-    fn flush(_vaddr: Option<usize>) {
-        // do nothing
+    fn flush(vaddr: Option<usize>) {
+        // hfence.gvma takes the guest physical address shifted right by 2, x0 for all
+        unsafe {
+            match vaddr {
+                Some(gpa) => {
+                    core::arch::asm!(".insn r 0x73, 0x0, 0x31, x0, {0}, x0", in(reg) gpa >> 2)
+                }
+                None => core::arch::asm!(".insn r 0x73, 0x0, 0x31, x0, x0, x0"),
+            }
+        }
     }
 }
 
@@ -1,11 +1,33 @@
 //! SBI call wrappers
 
 #![allow(unused)]
+use crate::arch::riscv::timer::{arm_host_timer, ticks_to_ns};
+use crate::config::DTB_ADDR;
+use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM};
+use crate::memory::addr::phys_to_virt;
+use crate::memory::MemFlags;
 use crate::percpu::get_cpu_data;
+use crate::zone::{remove_zone, zone_count, CpuSet};
+use core::ptr::{addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
 
 use super::cpu::ArchCpu;
+use super::guestmem::hlvd;
+use super::pmu::{SBI_PMU_CFG_FLAG_AUTO_START, SBI_PMU_FID};
 use crate::arch::riscv::csr::*;
 use riscv::register::{hvip, sie};
+/// legacy SBI v0.1 extensions, the eid is the function
+pub mod SBI_LEGACY_EID {
+    pub const SET_TIMER: usize = 0x00;
+    pub const CONSOLE_PUTCHAR: usize = 0x01;
+    pub const CONSOLE_GETCHAR: usize = 0x02;
+    pub const CLEAR_IPI: usize = 0x03;
+    pub const SEND_IPI: usize = 0x04;
+    pub const REMOTE_FENCE_I: usize = 0x05;
+    pub const REMOTE_SFENCE_VMA: usize = 0x06;
+    pub const REMOTE_SFENCE_VMA_ASID: usize = 0x07;
+    pub const SHUTDOWN: usize = 0x08;
+}
 pub mod SBI_EID {
     pub const BASE_EXTID: usize = 0x10;
     pub const SET_TIMER: usize = 0x54494D45;
@@ -13,7 +35,93 @@ pub mod SBI_EID {
     pub const SEND_IPI: usize = 0x735049;
     pub const RFENCE: usize = 0x52464E43;
     pub const PMU: usize = 0x504D55;
+    pub const SUSP: usize = 0x53555350;
+    pub const SRST: usize = 0x53525354;
+    pub const DBCN: usize = 0x4442434E;
+    pub const STA: usize = 0x535441;
+}
+/// extensions emulated by sbi_vs_handler, the only ones reported by probe_extension
+/// along with the legacy extensions of sbi_legacy_handler
+pub const SBI_VIRT_EXTENSIONS: [usize; 10] = [
+    SBI_EID::BASE_EXTID,
+    SBI_EID::SET_TIMER,
+    SBI_EID::EXTID_HSM,
+    SBI_EID::SEND_IPI,
+    SBI_EID::RFENCE,
+    SBI_EID::SRST,
+    SBI_EID::DBCN,
+    SBI_EID::STA,
+    SBI_EID::PMU,
+    SBI_EID::SUSP,
+];
+/// SBI specification version emulated for guests: v2.0
+pub const SBI_SPEC_VERSION: usize = 2 << 24;
+/// implementation id reported to guests, outside the ids assigned by the SBI spec
+pub const SBI_IMPL_ID_HVISOR: usize = 0x6876;
+pub mod SBI_BASE_FID {
+    pub const GET_SPEC_VERSION: usize = 0;
+    pub const GET_IMPL_ID: usize = 1;
+    pub const GET_IMPL_VERSION: usize = 2;
+    pub const PROBE_EXTENSION: usize = 3;
+    pub const GET_MVENDORID: usize = 4;
+    pub const GET_MARCHID: usize = 5;
+    pub const GET_MIMPID: usize = 6;
+}
+pub mod SBI_HSM_FID {
+    pub const HART_START: usize = 0;
+    pub const HART_STOP: usize = 1;
+    pub const HART_GET_STATUS: usize = 2;
+    pub const HART_SUSPEND: usize = 3;
+}
+pub mod SBI_IPI_FID {
+    pub const SEND_IPI: usize = 0;
+}
+pub mod SBI_RFENCE_FID {
+    pub const FENCE_I: usize = 0;
+    pub const SFENCE_VMA: usize = 1;
+    pub const SFENCE_VMA_ASID: usize = 2;
+}
+/// hart states of the SBI HSM extension, kept per vcpu in `ArchCpu::hsm_state`
+pub mod SBI_HSM_STATE {
+    pub const STARTED: usize = 0;
+    pub const STOPPED: usize = 1;
+    pub const START_PENDING: usize = 2;
+    pub const STOP_PENDING: usize = 3;
+    pub const SUSPENDED: usize = 4;
+    pub const SUSPEND_PENDING: usize = 5;
+    pub const RESUME_PENDING: usize = 6;
+}
+pub mod SBI_DBCN_FID {
+    pub const CONSOLE_WRITE: usize = 0;
+    pub const CONSOLE_READ: usize = 1;
+    pub const CONSOLE_WRITE_BYTE: usize = 2;
 }
+/// bytes moved between guest memory and the zone console at a time
+const SBI_DBCN_CHUNK_SIZE: usize = 64;
+pub mod SBI_STA_FID {
+    pub const STEAL_TIME_SET_SHMEM: usize = 0;
+}
+/// steal-time record shared with the guest, 64 bytes aligned to 64 bytes
+#[repr(C)]
+pub struct SbiStaRecord {
+    sequence: u32,
+    flags: u32,
+    /// nanoseconds this vcpu could not run the guest
+    steal: u64,
+    preempted: u8,
+    pad: [u8; 47],
+}
+pub mod SBI_SUSP_FID {
+    pub const SYSTEM_SUSPEND: usize = 0;
+}
+pub const SBI_SUSP_SLEEP_TYPE_SUSPEND_TO_RAM: u32 = 0;
+pub mod SBI_SRST_TYPE {
+    pub const SHUTDOWN: u32 = 0;
+    pub const COLD_REBOOT: u32 = 1;
+    pub const WARM_REBOOT: u32 = 2;
+}
+pub const SBI_HSM_SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+pub const SBI_HSM_SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
 pub const SBI_SUCCESS: i64 = 0;
 pub const SBI_ERR_FAILURE: i64 = -1;
 pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
@@ -22,8 +130,8 @@ pub const SBI_ERR_DENIED: i64 = -4;
 pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
 pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
 pub struct SbiRet {
-    error: i64,
-    value: i64,
+    pub error: i64,
+    pub value: i64,
 }
 /// use sbi call to putchar in console (qemu uart handler)
 pub fn console_putchar(c: usize) {
@@ -58,26 +166,22 @@ pub fn sbi_vs_handler(current_cpu: &mut ArchCpu) {
     let fid: usize = current_cpu.x[16];
     let sbi_ret;
 
+    if eid <= SBI_LEGACY_EID::SHUTDOWN {
+        // legacy calls only return a0, a1 is preserved
+        current_cpu.x[10] = sbi_legacy_handler(eid, current_cpu) as usize;
+        return;
+    }
     match eid {
-        //SBI_EXTID_BASE => sbi_ret = sbi_base_handler(fid, current_cpu),
         SBI_EID::BASE_EXTID => {
             trace!("SBI_EID::BASE,fid:{:#x}", fid);
-            sbi_ret = sbi_call_5(
-                eid,
-                fid,
-                current_cpu.x[10],
-                current_cpu.x[11],
-                current_cpu.x[12],
-                current_cpu.x[13],
-                current_cpu.x[14],
-            );
+            sbi_ret = sbi_base_handler(fid, current_cpu);
         }
         SBI_EID::SET_TIMER => {
             //debug!("SBI_EID::SET_TIMER on CPU {}", current_cpu.hartid);
             sbi_ret = sbi_time_handler(fid, current_cpu);
         }
         SBI_EID::EXTID_HSM => {
-            warn!("SBI_EID::EXTID_HSM on CPU {}", current_cpu.hartid);
+            debug!("SBI_EID::EXTID_HSM on CPU {}", current_cpu.hartid);
             sbi_ret = sbi_hsm_handler(fid, current_cpu);
         }
         SBI_EID::SEND_IPI => {
@@ -87,39 +191,31 @@ pub fn sbi_vs_handler(current_cpu: &mut ArchCpu) {
                 current_cpu.x[10],
                 current_cpu.x[11]
             );
-            sbi_ret = sbi_call_5(
-                eid,
-                fid,
-                current_cpu.x[10],
-                current_cpu.x[11],
-                current_cpu.x[12],
-                current_cpu.x[13],
-                current_cpu.x[14],
-            );
+            sbi_ret = sbi_hart_mask_handler(eid, fid, current_cpu);
         }
         SBI_EID::RFENCE => {
             trace!("SBI_EID::RFENCE,mask:{:#x}", current_cpu.x[10]);
-            sbi_ret = sbi_call_5(
-                eid,
-                fid,
-                current_cpu.x[10],
-                current_cpu.x[11],
-                current_cpu.x[12],
-                current_cpu.x[13],
-                current_cpu.x[14],
-            );
+            sbi_ret = sbi_hart_mask_handler(eid, fid, current_cpu);
+        }
+        SBI_EID::SRST => {
+            warn!("SBI_EID::SRST on CPU {}", current_cpu.hartid);
+            sbi_ret = sbi_srst_handler(fid, current_cpu);
+        }
+        SBI_EID::DBCN => {
+            trace!("SBI_EID::DBCN,fid:{:#x}", fid);
+            sbi_ret = sbi_dbcn_handler(fid, current_cpu);
+        }
+        SBI_EID::STA => {
+            trace!("SBI_EID::STA,fid:{:#x}", fid);
+            sbi_ret = sbi_sta_handler(fid, current_cpu);
+        }
+        SBI_EID::SUSP => {
+            warn!("SBI_EID::SUSP on CPU {}", current_cpu.hartid);
+            sbi_ret = sbi_susp_handler(fid, current_cpu);
         }
         SBI_EID::PMU => {
             trace!("SBI_EID::PMU,fid:{:#x}", fid);
-            sbi_ret = sbi_call_5(
-                eid,
-                fid,
-                current_cpu.x[10],
-                current_cpu.x[11],
-                current_cpu.x[12],
-                current_cpu.x[13],
-                current_cpu.x[14],
-            );
+            sbi_ret = sbi_pmu_handler(fid, current_cpu);
         }
         //_ => sbi_ret = sbi_dummy_handler(),
         _ => {
@@ -167,23 +263,206 @@ pub fn sbi_call_5(
     SbiRet { error, value }
 }
 
+/// translate a guest hart mask of zone-local hart ids into a mask of the physical harts
+/// owned by the zone, hart_mask_base == -1 means all harts of the zone
+fn translate_hart_mask(
+    current_cpu: &ArchCpu,
+    hart_mask: usize,
+    hart_mask_base: usize,
+) -> Result<usize, i64> {
+    let zone = get_cpu_data(current_cpu.hartid)
+        .zone
+        .clone()
+        .ok_or(SBI_ERR_FAILURE)?;
+    let zone = zone.read();
+    let mut phart_mask: usize = 0;
+    if hart_mask_base == usize::MAX {
+        zone.cpu_set
+            .iter()
+            .for_each(|phart| phart_mask |= 1 << phart);
+        return Ok(phart_mask);
+    }
+    for i in 0..usize::BITS as usize {
+        if hart_mask & (1 << i) == 0 {
+            continue;
+        }
+        let phart = hart_mask_base
+            .checked_add(i)
+            .and_then(|vhart| zone.phart_of(vhart))
+            .ok_or(SBI_ERR_INVALID_PARAM)?;
+        phart_mask |= 1 << phart;
+    }
+    Ok(phart_mask)
+}
+/// forward IPI/RFENCE calls to firmware with the hart mask confined to the calling zone
+pub fn sbi_hart_mask_handler(eid: usize, fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let args = [current_cpu.x[12], current_cpu.x[13], current_cpu.x[14]];
+    sbi_hart_mask_call(
+        current_cpu,
+        eid,
+        fid,
+        current_cpu.x[10],
+        current_cpu.x[11],
+        args,
+    )
+}
+fn sbi_hart_mask_call(
+    current_cpu: &ArchCpu,
+    eid: usize,
+    fid: usize,
+    hart_mask: usize,
+    hart_mask_base: usize,
+    args: [usize; 3],
+) -> SbiRet {
+    match translate_hart_mask(current_cpu, hart_mask, hart_mask_base) {
+        Ok(phart_mask) => {
+            if eid == SBI_EID::SEND_IPI {
+                // the targets raise VSSIP when they take the host IPI
+                (0..MAX_CPU_NUM)
+                    .filter(|&phart| phart_mask & (1 << phart) != 0)
+                    .for_each(|phart| {
+                        get_cpu_data(phart)
+                            .arch_cpu
+                            .ipi_pending
+                            .store(true, Ordering::Release)
+                    });
+            }
+            sbi_call_5(eid, fid, phart_mask, 0, args[0], args[1], args[2])
+        }
+        Err(error) => {
+            warn!(
+                "CPU{} hart mask {:#x} base {:#x} out of its zone",
+                current_cpu.hartid, hart_mask, hart_mask_base
+            );
+            SbiRet { error, value: 0 }
+        }
+    }
+}
+/// read the hart mask a legacy call points to in guest virtual memory, a null pointer
+/// means all harts of the zone
+fn read_legacy_hart_mask(current_cpu: &ArchCpu, gva: usize) -> Result<(usize, usize), i64> {
+    if gva == 0 {
+        return Ok((0, usize::MAX));
+    }
+    if gva % core::mem::size_of::<usize>() != 0 {
+        return Err(SBI_ERR_INVALID_ADDRESS);
+    }
+    let hart_mask = hlvd(current_cpu, gva).map_err(|_| SBI_ERR_INVALID_ADDRESS)?;
+    Ok((hart_mask as usize, 0))
+}
+/// legacy IPI and remote fence calls, translated to the IPI and RFENCE extensions
+fn sbi_legacy_hart_mask_call(current_cpu: &ArchCpu, eid: usize, fid: usize) -> i64 {
+    let (hart_mask, hart_mask_base) = match read_legacy_hart_mask(current_cpu, current_cpu.x[10]) {
+        Ok(mask) => mask,
+        Err(error) => return error,
+    };
+    // the legacy calls take the fence range and asid right after the hart mask
+    let args = [current_cpu.x[11], current_cpu.x[12], current_cpu.x[13]];
+    sbi_hart_mask_call(current_cpu, eid, fid, hart_mask, hart_mask_base, args).error
+}
+/// hvisor version encoded as major << 16 | minor << 8 | patch
+fn sbi_impl_version() -> usize {
+    let major: usize = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+    let minor: usize = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+    let patch: usize = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap();
+    major << 16 | minor << 8 | patch
+}
+pub fn sbi_base_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    match fid {
+        SBI_BASE_FID::GET_SPEC_VERSION => {
+            sbi_ret.value = SBI_SPEC_VERSION as i64;
+        }
+        SBI_BASE_FID::GET_IMPL_ID => {
+            sbi_ret.value = SBI_IMPL_ID_HVISOR as i64;
+        }
+        SBI_BASE_FID::GET_IMPL_VERSION => {
+            sbi_ret.value = sbi_impl_version() as i64;
+        }
+        SBI_BASE_FID::PROBE_EXTENSION => {
+            let extension = current_cpu.x[10];
+            sbi_ret.value = (extension <= SBI_LEGACY_EID::SHUTDOWN
+                || SBI_VIRT_EXTENSIONS.contains(&extension)) as i64;
+            debug!(
+                "CPU{} probe extension {:#x}: {}",
+                current_cpu.hartid, extension, sbi_ret.value
+            );
+        }
+        SBI_BASE_FID::GET_MVENDORID | SBI_BASE_FID::GET_MARCHID | SBI_BASE_FID::GET_MIMPID => {
+            // machine ids are those of the real hart
+            sbi_ret = sbi_call_5(SBI_EID::BASE_EXTID, fid, 0, 0, 0, 0, 0);
+        }
+        _ => {
+            warn!("Unsupported BASE function {:#x}", fid);
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        }
+    }
+    sbi_ret
+}
+/// emulate the legacy SBI v0.1 calls used by bare-metal guests
+pub fn sbi_legacy_handler(eid: usize, current_cpu: &mut ArchCpu) -> i64 {
+    match eid {
+        SBI_LEGACY_EID::SET_TIMER => sbi_time_handler(0, current_cpu).error,
+        SBI_LEGACY_EID::CONSOLE_PUTCHAR => {
+            let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+            let c = current_cpu.x[10] as u8;
+            zone.read().console.lock().write(&[c]);
+            SBI_SUCCESS
+        }
+        SBI_LEGACY_EID::CONSOLE_GETCHAR => {
+            let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+            let c = zone.read().console.lock().getchar();
+            c.map_or(-1, |c| c as i64)
+        }
+        SBI_LEGACY_EID::CLEAR_IPI => {
+            // VSSIP
+            clear_csr!(CSR_HVIP, 1 << 2);
+            SBI_SUCCESS
+        }
+        SBI_LEGACY_EID::SEND_IPI => {
+            sbi_legacy_hart_mask_call(current_cpu, SBI_EID::SEND_IPI, SBI_IPI_FID::SEND_IPI)
+        }
+        SBI_LEGACY_EID::REMOTE_FENCE_I => {
+            sbi_legacy_hart_mask_call(current_cpu, SBI_EID::RFENCE, SBI_RFENCE_FID::FENCE_I)
+        }
+        SBI_LEGACY_EID::REMOTE_SFENCE_VMA => {
+            sbi_legacy_hart_mask_call(current_cpu, SBI_EID::RFENCE, SBI_RFENCE_FID::SFENCE_VMA)
+        }
+        SBI_LEGACY_EID::REMOTE_SFENCE_VMA_ASID => sbi_legacy_hart_mask_call(
+            current_cpu,
+            SBI_EID::RFENCE,
+            SBI_RFENCE_FID::SFENCE_VMA_ASID,
+        ),
+        SBI_LEGACY_EID::SHUTDOWN => {
+            info!("CPU{} legacy shutdown", current_cpu.hartid);
+            zone_shutdown(current_cpu)
+        }
+        _ => {
+            warn!(
+                "Unsupported legacy SBI call eid {:#x} on CPU {}",
+                eid, current_cpu.hartid
+            );
+            SBI_ERR_NOT_SUPPORTED
+        }
+    }
+}
 pub fn sbi_time_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0,
     };
     let stime = current_cpu.x[10];
-    warn!("SBI_SET_TIMER stime: {:#x}", stime);
+    trace!("SBI_SET_TIMER stime: {:#x}", stime);
     if current_cpu.sstc {
         write_csr!(CSR_VSTIMECMP, stime);
     } else {
-        set_timer(stime);
-        unsafe {
-            // clear guest timer interrupt pending
-            hvip::clear_vstip();
-            // enable timer interrupt
-            sie::set_stimer();
-        }
+        current_cpu.guest_timer = stime;
+        arm_host_timer(current_cpu);
+        // clear guest timer interrupt pending
+        unsafe { hvip::clear_vstip() };
     }
     //debug!("SBI_SET_TIMER stime: {:#x}", stime);
     return sbi_ret;
@@ -194,43 +473,544 @@ pub fn sbi_hsm_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
         value: 0,
     };
     match fid {
-        0 => {
-            // hsm start
+        SBI_HSM_FID::HART_START => {
             sbi_ret = sbi_hsm_start_handler(current_cpu);
         }
+        SBI_HSM_FID::HART_STOP => {
+            sbi_ret = sbi_hsm_stop_handler(current_cpu);
+        }
+        SBI_HSM_FID::HART_GET_STATUS => {
+            sbi_ret = sbi_hsm_status_handler(current_cpu);
+        }
+        SBI_HSM_FID::HART_SUSPEND => {
+            sbi_ret = sbi_hsm_suspend_handler(current_cpu);
+        }
         _ => {
             error!("Unsupported HSM function {:#x}", fid);
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
         }
     }
     sbi_ret
 }
+/// physical hart behind guest hart id `vhart` of the zone running on `current_cpu`
+fn zone_phart_of(current_cpu: &ArchCpu, vhart: usize) -> Option<usize> {
+    get_cpu_data(current_cpu.hartid)
+        .zone
+        .as_ref()
+        .and_then(|zone| zone.read().phart_of(vhart))
+}
+/// move a stopped hart to START_PENDING and kick it, it will enter the guest at `start_addr`
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> i64 {
+    let target_cpu = get_cpu_data(hartid);
+    let _lock = target_cpu.ctrl_lock.lock();
+    if target_cpu.arch_cpu.hsm_state.load(Ordering::Acquire) != SBI_HSM_STATE::STOPPED {
+        return SBI_ERR_ALREADY_AVAILABLE;
+    }
+    target_cpu.cpu_on_entry = start_addr;
+    target_cpu.arch_cpu.start_addr = start_addr;
+    target_cpu.arch_cpu.start_opaque = opaque;
+    target_cpu
+        .arch_cpu
+        .hsm_state
+        .store(SBI_HSM_STATE::START_PENDING, Ordering::Release);
+    let _ret = sbi_rt::send_ipi(1 << hartid, 0);
+    debug!(
+        "send ipi to CPU{} ret: {} {}",
+        hartid, _ret.error, _ret.value
+    );
+    SBI_SUCCESS
+}
 pub fn sbi_hsm_start_handler(current_cpu: &mut ArchCpu) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0,
     };
     let hartid = current_cpu.x[10];
-
-    if (hartid == current_cpu.hartid) {
-        sbi_ret.error = SBI_ERR_ALREADY_AVAILABLE;
+    let start_addr = current_cpu.x[11];
+    let opaque = current_cpu.x[12];
+    debug!(
+        "@CPU{} hartid: {:#x}, start_addr: {:#x}, opaque: {:#x}",
+        current_cpu.hartid, hartid, start_addr, opaque
+    );
+    match zone_phart_of(current_cpu, hartid) {
+        Some(_) if !guest_can_execute(current_cpu, start_addr) => {
+            sbi_ret.error = SBI_ERR_INVALID_ADDRESS;
+        }
+        Some(phart) => sbi_ret.error = hart_start(phart, start_addr, opaque),
+        None => {
+            warn!(
+                "CPU{} try to start CPU{} out of its zone",
+                current_cpu.hartid, hartid
+            );
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+        }
+    }
+    sbi_ret
+}
+pub fn sbi_hsm_stop_handler(current_cpu: &mut ArchCpu) -> SbiRet {
+    // a successful hart_stop never returns to the caller
+    current_cpu.stop()
+}
+pub fn sbi_hsm_status_handler(current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let hartid = current_cpu.x[10];
+    match zone_phart_of(current_cpu, hartid) {
+        Some(phart) => {
+            sbi_ret.value = get_cpu_data(phart)
+                .arch_cpu
+                .hsm_state
+                .load(Ordering::Acquire) as i64;
+        }
+        None => sbi_ret.error = SBI_ERR_INVALID_PARAM,
+    }
+    sbi_ret
+}
+pub fn sbi_hsm_suspend_handler(current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let suspend_type = current_cpu.x[10] as u32;
+    let (resume_addr, opaque) = (current_cpu.x[11], current_cpu.x[12]);
+    match suspend_type {
+        SBI_HSM_SUSPEND_RETENTIVE => {
+            // any pending interrupt resumes the hart, it is handled after returning to the guest
+            hart_suspend(current_cpu);
+        }
+        SBI_HSM_SUSPEND_NON_RETENTIVE => {
+            if !guest_can_execute(current_cpu, resume_addr) {
+                sbi_ret.error = SBI_ERR_INVALID_ADDRESS;
+                return sbi_ret;
+            }
+            hart_suspend_non_retentive(current_cpu, resume_addr, opaque);
+        }
+        0x0000_0001..=0x0FFF_FFFF | 0x8000_0001..=0x8FFF_FFFF => {
+            // reserved suspend types
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+        }
+        _ => {
+            warn!(
+                "Unsupported HSM suspend type {:#x} on CPU {}",
+                suspend_type, current_cpu.hartid
+            );
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        }
+    }
+    sbi_ret
+}
+/// suspend the calling hart until an interrupt is pending, the state only leaves
+/// STARTED/SUSPENDED when the zone asks the hart to stop
+fn hart_suspend(current_cpu: &mut ArchCpu) {
+    if current_cpu
+        .hsm_state
+        .compare_exchange(
+            SBI_HSM_STATE::STARTED,
+            SBI_HSM_STATE::SUSPENDED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+        || !current_cpu.wait_for_interrupt(SBI_HSM_STATE::SUSPENDED)
+        || current_cpu
+            .hsm_state
+            .compare_exchange(
+                SBI_HSM_STATE::SUSPENDED,
+                SBI_HSM_STATE::STARTED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+    {
+        current_cpu.stop();
+    }
+}
+/// suspend the calling hart losing its guest context, it resumes at `resume_addr`
+/// like a started hart
+fn hart_suspend_non_retentive(current_cpu: &mut ArchCpu, resume_addr: usize, opaque: usize) -> ! {
+    current_cpu.vpmu.save();
+    hart_suspend(current_cpu);
+    current_cpu.vpmu.restore();
+    current_cpu.resume(resume_addr, opaque);
+    current_cpu.run();
+    unreachable!()
+}
+/// check that the zone may run code at `addr`
+fn guest_can_execute(current_cpu: &ArchCpu, addr: usize) -> bool {
+    let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+    let ret = zone.write().guest_to_host(addr, MemFlags::EXECUTE).is_ok();
+    ret
+}
+/// ask a hart of the zone to stop, a running hart parks itself when it takes the IPI
+pub fn hart_stop_request(hartid: usize) {
+    let target_cpu = get_cpu_data(hartid);
+    let _lock = target_cpu.ctrl_lock.lock();
+    let hsm_state = &target_cpu.arch_cpu.hsm_state;
+    loop {
+        let state = hsm_state.load(Ordering::Acquire);
+        let next = match state {
+            SBI_HSM_STATE::STARTED | SBI_HSM_STATE::SUSPENDED => SBI_HSM_STATE::STOP_PENDING,
+            // cancel a start the hart has not seen yet
+            SBI_HSM_STATE::START_PENDING => SBI_HSM_STATE::STOPPED,
+            _ => return,
+        };
+        if hsm_state
+            .compare_exchange(state, next, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            if next == SBI_HSM_STATE::STOP_PENDING {
+                sbi_rt::send_ipi(1 << hartid, 0);
+            }
+            return;
+        }
+    }
+}
+/// stop all other harts of the zone and wait until they are parked
+fn zone_stop_other_harts(cpu_set: CpuSet, this_cpu: usize) {
+    cpu_set
+        .iter_except(this_cpu)
+        .for_each(|cpuid| hart_stop_request(cpuid));
+    cpu_set.iter_except(this_cpu).for_each(|cpuid| {
+        while get_cpu_data(cpuid)
+            .arch_cpu
+            .hsm_state
+            .load(Ordering::Acquire)
+            != SBI_HSM_STATE::STOPPED
+        {
+            hart_stop_request(cpuid);
+            core::hint::spin_loop();
+        }
+    });
+}
+/// power off the zone running on `current_cpu`, the other zones keep running
+pub fn zone_shutdown(current_cpu: &mut ArchCpu) -> ! {
+    let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+    if zone.read().resetting.swap(true, Ordering::AcqRel) {
+        // another hart of the zone is already resetting it
+        current_cpu.stop();
+    }
+    let cpu_set = zone.read().cpu_set;
+    drop(zone);
+    zone_stop_other_harts(cpu_set, current_cpu.hartid);
+    zone_remove(current_cpu)
+}
+/// remove the zone running on `current_cpu`, whose other harts are stopped, and stop the
+/// hart
+fn zone_remove(current_cpu: &mut ArchCpu) -> ! {
+    let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+    let vmid = zone.read().vmid;
+    zone.read().console.lock().flush();
+    zone.read().release_irqs();
+    remove_zone(vmid);
+    // the last reference, free the zone memory set
+    drop(zone);
+    info!("zone {} shutdown", vmid);
+    if zone_count() == 0 {
+        info!("all zones are down, shutdown the machine");
+        shutdown(false);
+    }
+    current_cpu.stop()
+}
+/// reboot the zone running on `current_cpu` from its original image and device tree, the
+/// zone is shut down if the image can not be reloaded
+pub fn zone_reboot(current_cpu: &mut ArchCpu) -> ! {
+    let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+    if zone.read().resetting.swap(true, Ordering::AcqRel) {
+        current_cpu.stop();
+    }
+    let cpu_set = zone.read().cpu_set;
+    zone_stop_other_harts(cpu_set, current_cpu.hartid);
+    let loaded = {
+        let mut zone = zone.write();
+        zone.release_irqs();
+        match zone.load() {
+            Ok(()) => {
+                zone.resetting.store(false, Ordering::Release);
+                info!("zone {} reboot", zone.vmid);
+                Some((zone.entry, zone.dtb_addr))
+            }
+            Err(e) => {
+                error!("zone {} reboot failed, shut it down: {:?}", zone.vmid, e);
+                None
+            }
+        }
+    };
+    drop(zone);
+    let (entry, dtb_addr) = match loaded {
+        Some(boot) => boot,
+        // the guest memory may be partly overwritten, the zone can not go on
+        None => zone_remove(current_cpu),
+    };
+    let boot_cpu = cpu_set.first_cpu().unwrap();
+    if boot_cpu == current_cpu.hartid {
+        current_cpu.reset(entry, dtb_addr);
+        current_cpu.run();
+        unreachable!()
     } else {
-        //TODO:add sbi conext in archcpu
-        let hartid = current_cpu.x[10];
-        let start_addr = current_cpu.x[11];
-        let opaque = current_cpu.x[12];
-        let target_cpu = get_cpu_data(hartid);
-        target_cpu.cpu_on_entry = start_addr;
-        target_cpu.arch_cpu.sepc = start_addr;
-        target_cpu.arch_cpu.x[11] = opaque;
-        warn!(
-            "@CPU{} hartid: {:#x}, start_addr: {:#x}, opaque: {:#x}",
-            current_cpu.hartid, hartid, start_addr, opaque
-        );
-        let _ret = sbi_rt::send_ipi(1 << hartid, 0);
-        warn!(
-            "send ipi to CPU{} ret: {} {}",
-            hartid, _ret.error, _ret.value
+        hart_start(boot_cpu, entry, dtb_addr);
+        current_cpu.stop()
+    }
+}
+pub fn sbi_srst_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    if fid != 0 {
+        sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        return sbi_ret;
+    }
+    let reset_type = current_cpu.x[10] as u32;
+    let reset_reason = current_cpu.x[11] as u32;
+    info!(
+        "CPU{} system reset type {:#x} reason {:#x}",
+        current_cpu.hartid, reset_type, reset_reason
+    );
+    match reset_type {
+        SBI_SRST_TYPE::SHUTDOWN => zone_shutdown(current_cpu),
+        SBI_SRST_TYPE::COLD_REBOOT | SBI_SRST_TYPE::WARM_REBOOT => zone_reboot(current_cpu),
+        0x0000_0003..=0xEFFF_FFFF => {
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+        }
+        _ => {
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        }
+    }
+    sbi_ret
+}
+pub fn sbi_dbcn_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+    let mut zone = zone.write();
+    let num_bytes = current_cpu.x[10];
+    // the guest buffer is a physical address split in two registers, only the low part is used on rv64
+    let base_addr = current_cpu.x[11];
+    if current_cpu.x[12] != 0
+        && (fid == SBI_DBCN_FID::CONSOLE_WRITE || fid == SBI_DBCN_FID::CONSOLE_READ)
+    {
+        sbi_ret.error = SBI_ERR_INVALID_PARAM;
+        return sbi_ret;
+    }
+    let mut buf = [0u8; SBI_DBCN_CHUNK_SIZE];
+    match fid {
+        SBI_DBCN_FID::CONSOLE_WRITE => {
+            let mut written = 0;
+            while written < num_bytes {
+                let len = (num_bytes - written).min(SBI_DBCN_CHUNK_SIZE);
+                if zone
+                    .read_guest(base_addr + written, &mut buf[..len])
+                    .is_err()
+                {
+                    sbi_ret.error = SBI_ERR_INVALID_PARAM;
+                    break;
+                }
+                let mut console = zone.console.lock();
+                buf[..len].iter().for_each(|&c| console.putchar(c));
+                written += len;
+            }
+            zone.console.lock().flush();
+            sbi_ret.value = written as i64;
+        }
+        SBI_DBCN_FID::CONSOLE_READ => {
+            let console = zone.console.lock();
+            let mut len = 0;
+            while len < num_bytes.min(SBI_DBCN_CHUNK_SIZE) {
+                match console.getchar() {
+                    Some(c) => buf[len] = c,
+                    None => break,
+                }
+                len += 1;
+            }
+            drop(console);
+            if zone.write_guest(base_addr, &buf[..len]).is_err() {
+                sbi_ret.error = SBI_ERR_INVALID_PARAM;
+            } else {
+                sbi_ret.value = len as i64;
+            }
+        }
+        SBI_DBCN_FID::CONSOLE_WRITE_BYTE => {
+            zone.console.lock().write(&[current_cpu.x[10] as u8]);
+        }
+        _ => {
+            warn!("Unsupported DBCN function {:#x}", fid);
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        }
+    }
+    sbi_ret
+}
+pub fn sbi_sta_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    if fid != SBI_STA_FID::STEAL_TIME_SET_SHMEM {
+        sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        return sbi_ret;
+    }
+    let (shmem_lo, shmem_hi, flags) = (current_cpu.x[10], current_cpu.x[11], current_cpu.x[12]);
+    if flags != 0 {
+        sbi_ret.error = SBI_ERR_INVALID_PARAM;
+    } else if shmem_lo == usize::MAX && shmem_hi == usize::MAX {
+        // disable steal-time reporting
+        current_cpu.sta_record = INVALID_ADDRESS;
+    } else if shmem_lo % core::mem::size_of::<SbiStaRecord>() != 0 {
+        sbi_ret.error = SBI_ERR_INVALID_PARAM;
+    } else {
+        let zone = get_cpu_data(current_cpu.hartid).zone.clone().unwrap();
+        // the aligned record never crosses a page, translating its start is enough
+        match zone
+            .write()
+            .guest_to_host(shmem_lo, MemFlags::READ | MemFlags::WRITE)
+        {
+            Ok(hpa) if shmem_hi == 0 => {
+                debug!(
+                    "CPU{} steal-time record at {:#x}",
+                    current_cpu.hartid, shmem_lo
+                );
+                current_cpu.sta_record = hpa;
+            }
+            _ => sbi_ret.error = SBI_ERR_INVALID_ADDRESS,
+        }
+    }
+    sbi_ret
+}
+/// add `ticks` of hypervisor time to the steal time published to the guest, vcpus are
+/// pinned to their harts so this is the only time a zone loses its cpu
+pub fn sta_account(current_cpu: &mut ArchCpu, ticks: usize) {
+    if current_cpu.sta_record == INVALID_ADDRESS {
+        return;
+    }
+    let record = phys_to_virt(current_cpu.sta_record) as *mut SbiStaRecord;
+    // the guest retries its read while the sequence is odd or changed
+    unsafe {
+        let sequence = addr_of_mut!((*record).sequence);
+        let steal = addr_of_mut!((*record).steal);
+        write_volatile(sequence, read_volatile(sequence).wrapping_add(1));
+        fence(Ordering::SeqCst);
+        write_volatile(steal, read_volatile(steal) + ticks_to_ns(ticks) as u64);
+        write_volatile(addr_of_mut!((*record).preempted), 0);
+        fence(Ordering::SeqCst);
+        write_volatile(sequence, read_volatile(sequence).wrapping_add(1));
+    }
+}
+/// SBI PMU on the counters owned by the zone, guest counter indices are translated
+/// to physical ones and back
+pub fn sbi_pmu_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let vpmu = &mut current_cpu.vpmu;
+    let args = &current_cpu.x[10..15];
+    match fid {
+        SBI_PMU_FID::NUM_COUNTERS => {
+            sbi_ret.value = vpmu.num_counters() as i64;
+        }
+        SBI_PMU_FID::COUNTER_GET_INFO => match vpmu.counter_info(args[0]) {
+            Some(info) => sbi_ret.value = info as i64,
+            None => sbi_ret.error = SBI_ERR_INVALID_PARAM,
+        },
+        SBI_PMU_FID::COUNTER_CONFIG_MATCHING => match vpmu.phys_mask(args[0], args[1]) {
+            Some(phys_mask) => {
+                sbi_ret = sbi_call_5(SBI_EID::PMU, fid, 0, phys_mask, args[2], args[3], args[4]);
+                if sbi_ret.error == SBI_SUCCESS {
+                    let pidx = sbi_ret.value as usize;
+                    match vpmu.virt_idx(pidx) {
+                        Some(vidx) => {
+                            if args[2] & SBI_PMU_CFG_FLAG_AUTO_START != 0 {
+                                vpmu.set_started(1 << pidx, true);
+                            }
+                            sbi_ret.value = vidx as i64;
+                        }
+                        None => {
+                            // the firmware picked a counter outside of the mask it was given
+                            error!(
+                                "PMU counter {} not owned by CPU{}",
+                                pidx, current_cpu.hartid
+                            );
+                            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+                            sbi_ret.value = 0;
+                        }
+                    }
+                }
+            }
+            None => sbi_ret.error = SBI_ERR_INVALID_PARAM,
+        },
+        SBI_PMU_FID::COUNTER_START | SBI_PMU_FID::COUNTER_STOP => {
+            match vpmu.phys_mask(args[0], args[1]) {
+                Some(phys_mask) => {
+                    sbi_ret = sbi_call_5(SBI_EID::PMU, fid, 0, phys_mask, args[2], args[3], 0);
+                    if sbi_ret.error == SBI_SUCCESS {
+                        vpmu.set_started(phys_mask, fid == SBI_PMU_FID::COUNTER_START);
+                    }
+                }
+                None => sbi_ret.error = SBI_ERR_INVALID_PARAM,
+            }
+        }
+        SBI_PMU_FID::COUNTER_FW_READ | SBI_PMU_FID::COUNTER_FW_READ_HI => {
+            match vpmu.phys_idx(args[0]) {
+                Some(pidx) => sbi_ret = sbi_call_5(SBI_EID::PMU, fid, pidx, 0, 0, 0, 0),
+                None => sbi_ret.error = SBI_ERR_INVALID_PARAM,
+            }
+        }
+        _ => {
+            warn!("Unsupported PMU function {:#x}", fid);
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        }
+    }
+    sbi_ret
+}
+/// SBI system suspend of the calling zone, the other zones keep running
+pub fn sbi_susp_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    if fid != SBI_SUSP_FID::SYSTEM_SUSPEND {
+        sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+        return sbi_ret;
+    }
+    let sleep_type = current_cpu.x[10] as u32;
+    let (resume_addr, opaque) = (current_cpu.x[11], current_cpu.x[12]);
+    match sleep_type {
+        SBI_SUSP_SLEEP_TYPE_SUSPEND_TO_RAM => {}
+        0x0000_0001..=0x7FFF_FFFF => {
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+            return sbi_ret;
+        }
+        _ => {
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
+            return sbi_ret;
+        }
+    }
+    let cpu_set = get_cpu_data(current_cpu.hartid)
+        .zone
+        .clone()
+        .unwrap()
+        .read()
+        .cpu_set;
+    // the guest must have stopped all its other harts first
+    if cpu_set.iter_except(current_cpu.hartid).any(|cpuid| {
+        get_cpu_data(cpuid)
+            .arch_cpu
+            .hsm_state
+            .load(Ordering::Acquire)
+            != SBI_HSM_STATE::STOPPED
+    }) {
+        sbi_ret.error = SBI_ERR_DENIED;
+    } else if !guest_can_execute(current_cpu, resume_addr) {
+        sbi_ret.error = SBI_ERR_INVALID_ADDRESS;
+    } else {
+        info!(
+            "CPU{} suspend zone to ram, resume at {:#x}",
+            current_cpu.hartid, resume_addr
         );
+        hart_suspend_non_retentive(current_cpu, resume_addr, opaque);
     }
     sbi_ret
 }
@@ -1,11 +1,14 @@
 //! RISC-V timer-related functionality
 #![allow(dead_code)]
+use crate::arch::riscv::cpu::ArchCpu;
+use crate::arch::riscv::irqstorm::irq_storm_deadline;
 use crate::arch::riscv::sbi::set_timer;
-use riscv::register::time;
+use riscv::register::{sie, time};
 pub const CLOCK_FREQ: usize = 12500000;
 pub const MEMORY_END: usize = 0x88000000;
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
+const NSEC_PER_SEC: usize = 1000000000;
 ///get current time
 pub fn get_time() -> usize {
     time::read()
@@ -14,6 +17,16 @@ pub fn get_time() -> usize {
 pub fn get_time_ms() -> usize {
     time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
 }
+/// convert timer ticks to nanoseconds
+pub fn ticks_to_ns(ticks: usize) -> usize {
+    ticks * (NSEC_PER_SEC / CLOCK_FREQ)
+}
+/// program the host timer of the current hart for the earliest of the guest deadline it
+/// carries and the end of the irq storm backoffs
+pub fn arm_host_timer(current_cpu: &ArchCpu) {
+    set_timer(current_cpu.guest_timer.min(irq_storm_deadline()));
+    unsafe { sie::set_stimer() };
+}
 /// set the next timer interrupt
 pub fn set_next_trigger() {
     set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
@@ -1,18 +1,18 @@
 use super::cpu::ArchCpu;
-use super::plic::PLIC;
-use super::sbi::sbi_vs_handler;
-use crate::arch::riscv::plic::{host_plic, PLIC_GLOBAL_SIZE, PLIC_TOTAL_SIZE};
-use crate::arch::riscv::plic::{vplic_global_emul_handler, vplic_hart_emul_handler};
-use crate::arch::riscv::timer::{get_time, set_next_trigger};
+use super::irqchip::irqchip;
+use super::irqstorm::{irq_storm_account, irq_storm_poll};
+use super::misaligned::misaligned_handler;
+use super::s2fault::stage2_fault_handler;
+use super::sbi::{sbi_vs_handler, sta_account, SBI_HSM_STATE};
+use super::vcsr::virtual_inst_handler;
+use crate::arch::riscv::timer::{arm_host_timer, get_time, set_next_trigger};
 use crate::arch::riscv::{csr::*, trap};
-use crate::memory::{GuestPhysAddr, HostPhysAddr};
-use crate::percpu;
-use core::arch::{asm, global_asm};
+use core::arch::global_asm;
+use core::sync::atomic::Ordering;
 use core::time;
+use riscv::register::hvip;
 use riscv::register::mtvec::TrapMode;
 use riscv::register::{hcounteren, stvec};
-use riscv::register::{hvip, sie};
-use riscv_decode::Instruction;
 extern "C" {
     fn _hyp_trap_vector();
 }
@@ -20,9 +20,20 @@ global_asm!(include_str!("trap.S"),
 sync_exception_handler=sym sync_exception_handler,
 interrupts_arch_handle=sym interrupts_arch_handle);
 pub mod ExceptionType {
+    pub const INST_ACCESS_FAULT: usize = 1;
+    pub const ILLEGAL_INST: usize = 2;
+    pub const LOAD_MISALIGNED: usize = 4;
+    pub const LOAD_ACCESS_FAULT: usize = 5;
+    pub const STORE_MISALIGNED: usize = 6;
+    pub const STORE_ACCESS_FAULT: usize = 7;
     pub const ECALL_VU: usize = 8;
     pub const ECALL_VS: usize = 10;
+    pub const INST_PAGE_FAULT: usize = 12;
+    pub const LOAD_PAGE_FAULT: usize = 13;
+    pub const STORE_PAGE_FAULT: usize = 15;
+    pub const INST_GUEST_PAGE_FAULT: usize = 20;
     pub const LOAD_GUEST_PAGE_FAULT: usize = 21;
+    pub const VIRTUAL_INST: usize = 22;
     pub const STORE_GUEST_PAGE_FAULT: usize = 23;
 }
 
@@ -30,6 +41,7 @@ pub mod InterruptType {
     pub const SSI: usize = 1;
     pub const STI: usize = 5;
     pub const SEI: usize = 9;
+    pub const SGEI: usize = 12;
 }
 pub fn init() {
     unsafe {
@@ -57,124 +69,84 @@ pub fn sync_exception_handler(current_cpu: &mut ArchCpu) {
     match trap_code {
         ExceptionType::ECALL_VU => {
             error!("ECALL_VU");
+            inject_exception(current_cpu, ExceptionType::ECALL_VU, 0);
         }
         ExceptionType::ECALL_VS => {
             trace!("ECALL_VS");
             sbi_vs_handler(current_cpu);
             current_cpu.sepc += 4;
         }
-        ExceptionType::LOAD_GUEST_PAGE_FAULT => {
-            trace!("LOAD_GUEST_PAGE_FAULT");
-            guest_page_fault_handler(current_cpu);
+        ExceptionType::VIRTUAL_INST => {
+            trace!("VIRTUAL_INST");
+            virtual_inst_handler(current_cpu);
         }
-        ExceptionType::STORE_GUEST_PAGE_FAULT => {
-            debug!("STORE_GUEST_PAGE_FAULT");
-            guest_page_fault_handler(current_cpu);
+        ExceptionType::LOAD_MISALIGNED | ExceptionType::STORE_MISALIGNED => {
+            trace!("MISALIGNED");
+            misaligned_handler(current_cpu, trap_code);
+        }
+        ExceptionType::INST_GUEST_PAGE_FAULT
+        | ExceptionType::LOAD_GUEST_PAGE_FAULT
+        | ExceptionType::STORE_GUEST_PAGE_FAULT
+        | ExceptionType::INST_ACCESS_FAULT
+        | ExceptionType::LOAD_ACCESS_FAULT
+        | ExceptionType::STORE_ACCESS_FAULT => {
+            trace!("STAGE2_FAULT {}", trap_code);
+            let start = get_time();
+            stage2_fault_handler(current_cpu, trap_code);
+            sta_account(current_cpu, get_time() - start);
         }
         _ => {
-            warn!(
-                "CPU {} trap {},sepc: {:#x}",
-                current_cpu.hartid, trap_code, current_cpu.sepc
+            debug!(
+                "CPU {} trap {} sepc {:#x} htval {:#x} htinst {:#x}",
+                current_cpu.hartid, trap_code, current_cpu.sepc, trap_value, trap_ins
             );
-            warn!("trap info: {} {:#x} {:#x}", trap_code, trap_value, trap_ins);
-            let raw_inst = read_inst(trap_pc);
-            let inst = riscv_decode::decode(raw_inst);
-            warn!("trap ins: {:#x}  {:?}", raw_inst, inst);
-            current_cpu.sepc += 4;
-            panic!("unhandled trap");
-            current_cpu.idle();
-        }
-    }
-}
-pub fn guest_page_fault_handler(current_cpu: &mut ArchCpu) {
-    let addr: HostPhysAddr = read_csr!(CSR_HTVAL) << 2;
-    trace!("guest page fault at {:#x}", addr);
-    let host_plic_base = host_plic().read().base;
-    //TODO: get plic addr range from dtb or vpliv object
-    if addr >= host_plic_base && addr < host_plic_base + PLIC_TOTAL_SIZE {
-        trace!("PLIC access");
-        let mut inst: u32 = read_csr!(CSR_HTINST) as u32;
-        if inst == 0 {
-            let inst_addr: GuestPhysAddr = current_cpu.sepc;
-            //load real ins from guest memmory
-            inst = read_inst(inst_addr);
-        } else if inst == 0x3020 || inst == 0x3000 {
-            // TODO: we should reinject this in the guest as a fault access
-            error!("fault on 1st stage page table walk");
-        } else {
-            // If htinst is valid and is not a pseudo instructon make sure
-            // the opcode is valid even if it was a compressed instruction,
-            // but before save the real instruction size.
-            error!("unhandled guest page fault at {:#x}", addr);
-        }
-        //TODO: decode inst to real instruction
-        let (len, inst) = decode_inst(inst);
-        if let Some(inst) = inst {
-            if addr >= host_plic_base + PLIC_GLOBAL_SIZE {
-                vplic_hart_emul_handler(current_cpu, addr, inst);
-            } else {
-                vplic_global_emul_handler(current_cpu, addr, inst);
-            }
-            current_cpu.sepc += len;
-        } else {
-            error!("Invalid instruction at {:#x}", current_cpu.sepc);
+            // let the guest handle it, as if the exception was delegated
+            inject_exception(current_cpu, trap_code, read_csr!(CSR_STVAL));
         }
-    } else {
-        panic!("CPU {} unmaped memmory at {:#x}", current_cpu.hartid, addr);
     }
 }
-fn read_inst(addr: GuestPhysAddr) -> u32 {
-    let mut ins: u32 = 0;
-    if addr & 0b1 != 0 {
-        error!("trying to read guest unaligned instruction");
-    }
-    //
-    //  Read 16 bits at a time to make sure the access is aligned. If the instruction is not
-    //  compressed, read the following 16-bits.
-    //
-    ins = hlvxhu(addr) as u32;
-    if (ins & 0b11) == 3 {
-        ins |= (hlvxhu(addr + 2) as u32) << 16;
+/// raise exception `cause` in the guest running on `current_cpu` the way a delegated trap
+/// does: the guest resumes at its vstvec in VS mode with vsepc, vscause and vstval set
+pub fn inject_exception(current_cpu: &mut ArchCpu, cause: usize, tval: usize) {
+    debug!(
+        "CPU {} inject exception {} tval {:#x} at {:#x}",
+        current_cpu.hartid, cause, tval, current_cpu.sepc
+    );
+    write_csr!(CSR_VSEPC, current_cpu.sepc);
+    write_csr!(CSR_VSCAUSE, cause);
+    write_csr!(CSR_VSTVAL, tval);
+    // vsstatus.SPP takes the privilege the guest trapped from, SPIE takes SIE
+    let mut vsstatus = read_csr!(CSR_VSSTATUS) & !(SSTATUS_SPP | SSTATUS_SPIE);
+    if current_cpu.sstatus & SSTATUS_SPP != 0 {
+        vsstatus |= SSTATUS_SPP;
     }
-    ins
-}
-fn hlvxhu(addr: GuestPhysAddr) -> u64 {
-    let mut value: u64;
-    unsafe {
-        asm!(
-            ".insn r 0x73, 0x4, 0x32, {0}, {1}, x3",
-            out(reg) value,
-            in(reg) addr,
-        );
+    if vsstatus & SSTATUS_SIE != 0 {
+        vsstatus |= SSTATUS_SPIE;
     }
-    value
-}
-/// decode risc-v instruction, return (inst len, inst)
-fn decode_inst(inst: u32) -> (usize, Option<Instruction>) {
-    let i1 = inst as u16;
-    let len = riscv_decode::instruction_length(i1);
-    let inst = match len {
-        2 => i1 as u32,
-        4 => inst as u32,
-        _ => unreachable!(),
-    };
-    (len, riscv_decode::decode(inst).ok())
+    write_csr!(CSR_VSSTATUS, vsstatus & !SSTATUS_SIE);
+    // exceptions always go to the base address, even with vectored vstvec
+    current_cpu.sstatus |= SSTATUS_SPP;
+    current_cpu.sepc = read_csr!(CSR_VSTVEC) & !0b11;
 }
 /// handle external interrupt
 pub fn interrupts_arch_handle(current_cpu: &mut ArchCpu) {
     trace!("interrupts_arch_handle @CPU{}", current_cpu.hartid);
+    let start = get_time();
     let trap_code: usize;
     trap_code = read_csr!(CSR_SCAUSE);
     trace!("CSR_SCAUSE: {:#x}", trap_code);
     match trap_code & 0xfff {
         InterruptType::STI => {
             current_cpu.sti += 1;
+            irq_storm_poll();
             trace!("STI {} on CPU{}", current_cpu.sti, current_cpu.hartid);
             trace!("STI on CPU{}", current_cpu.hartid);
-            unsafe {
-                hvip::set_vstip();
-                sie::clear_stimer();
+            // the host timer also ends irq storm backoffs, only a due guest deadline is forwarded
+            if get_time() >= current_cpu.guest_timer {
+                current_cpu.guest_timer = usize::MAX;
+                unsafe { hvip::set_vstip() };
             }
+            arm_host_timer(current_cpu);
             trace!("sip{:#x}", read_csr!(CSR_SIP));
             trace!("sie {:#x}", read_csr!(CSR_SIE));
         }
@@ -190,6 +162,16 @@ pub fn interrupts_arch_handle(current_cpu: &mut ArchCpu) {
             debug!("SEI on CPU {}", current_cpu.hartid);
             handle_eirq(current_cpu)
         }
+        InterruptType::SGEI => {
+            // guest files are only watched while a vcpu waits in wfi, which has
+            // interrupts off; mask them if one slips through
+            trace!(
+                "SGEI on CPU{} hgeip {:#x}",
+                current_cpu.hartid,
+                read_csr!(CSR_HGEIP)
+            );
+            write_csr!(CSR_HGEIE, 0);
+        }
         _ => {
             error!(
                 "unhandled trap {:#x},sepc: {:#x}",
@@ -198,25 +180,25 @@ pub fn interrupts_arch_handle(current_cpu: &mut ArchCpu) {
             unreachable!();
         }
     }
+    sta_account(current_cpu, get_time() - start);
 }
 
 /// handle interrupt request(current only external interrupt)
 pub fn handle_eirq(current_cpu: &mut ArchCpu) {
-    // TODO: handle other irq
     // check external interrupt && handle
-    // sifive plic: context0=>cpu0,M mode,context1=>cpu0,S mode...
-    let context_id = 2 * current_cpu.hartid + 1;
-    let mut host_plic = host_plic();
-    let claim_and_complete_addr =
-        host_plic.read().base + PLIC_GLOBAL_SIZE + 0x1000 * context_id + 0x4;
-    let mut irq = unsafe { core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
-    debug!(
-        "CPU{} get external irq{}@{:#x}",
-        current_cpu.hartid, irq, claim_and_complete_addr
-    );
-    host_plic.write().claim_complete[context_id] = irq;
-    // set external interrupt pending, which trigger guest interrupt
-    unsafe { hvip::set_vseip() };
+    let chip = irqchip();
+    // take all pending irqs at once, they wait in the per-hart pending set for the guest
+    while let Some(irq) = chip.claim(current_cpu.hartid) {
+        debug!(
+            "CPU{} get external irq{} from {}",
+            current_cpu.hartid,
+            irq,
+            chip.name()
+        );
+        chip.inject(current_cpu, irq);
+        irq_storm_account(current_cpu, irq);
+    }
+    irq_storm_poll();
 }
 pub fn handle_ssi(current_cpu: &mut ArchCpu) {
     let sip = read_csr!(CSR_SIP);
@@ -224,7 +206,18 @@ pub fn handle_ssi(current_cpu: &mut ArchCpu) {
     clear_csr!(CSR_SIP, 1 << 1);
     let sip2 = read_csr!(CSR_SIP);
     trace!("CPU{} sip*: {:#x}", current_cpu.hartid, sip2);
-
+    if current_cpu.virq_kick.swap(false, Ordering::AcqRel) {
+        // the guest finds the source when it claims
+        unsafe { hvip::set_vseip() };
+    }
+    if current_cpu.hsm_state.load(Ordering::Acquire) == SBI_HSM_STATE::STOP_PENDING {
+        // the zone is shutting down or rebooting
+        current_cpu.stop();
+    }
+    // kicks and HSM requests share the host IPI, only a guest IPI raises VSSIP
+    if !current_cpu.ipi_pending.swap(false, Ordering::AcqRel) {
+        return;
+    }
     trace!("hvip: {:#x}", read_csr!(CSR_HVIP));
     set_csr!(CSR_HVIP, 1 << 2);
 }
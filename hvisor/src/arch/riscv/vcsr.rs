@@ -0,0 +1,188 @@
+//! Emulation of the CSRs the hardware forbids in VS mode, reached through
+//! virtual-instruction exceptions.
+//!
+//! Each emulated CSR has an entry in `CSR_EMUL_TABLE`, the accesses to other CSRs raise
+//! an illegal instruction exception in the guest. The wfi trapped by hstatus.VTW also
+//! lands here.
+use super::cpu::ArchCpu;
+use super::csr::*;
+use super::decode::read_inst;
+use super::pmu::CSR_CYCLE;
+use super::sbi::SBI_HSM_STATE;
+use super::trap::{inject_exception, ExceptionType};
+
+const OPCODE_SYSTEM: u32 = 0x73;
+const INST_WFI: u32 = 0x1050_0073;
+const CSR_SEED: usize = 0x015;
+const CSR_INSTRET: usize = 0xC02;
+const CSR_HPMCOUNTER3: usize = 0xC03;
+const CSR_HPMCOUNTER31: usize = 0xC1F;
+
+/// a decoded csrrw/csrrs/csrrc, or one of their immediate forms
+#[derive(Debug, Clone, Copy)]
+struct CsrInst {
+    csr: usize,
+    rd: usize,
+    op: CsrOp,
+    /// rs1 or uimm
+    src: usize,
+    imm: bool,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+impl CsrInst {
+    fn decode(inst: u32) -> Option<Self> {
+        if inst & 0x7f != OPCODE_SYSTEM {
+            return None;
+        }
+        let funct3 = (inst >> 12) & 0b111;
+        let op = match funct3 & 0b11 {
+            1 => CsrOp::Write,
+            2 => CsrOp::Set,
+            3 => CsrOp::Clear,
+            _ => return None,
+        };
+        Some(Self {
+            csr: (inst >> 20) as usize,
+            rd: ((inst >> 7) & 0x1f) as usize,
+            op,
+            src: ((inst >> 15) & 0x1f) as usize,
+            imm: funct3 & 0b100 != 0,
+        })
+    }
+    /// csrrs/csrrc with x0 or a zero immediate do not write the csr
+    fn writes(&self) -> bool {
+        self.op == CsrOp::Write || self.src != 0
+    }
+    /// csrrw with rd x0 does not read the csr, csrrs/csrrc always do
+    fn reads(&self) -> bool {
+        self.op != CsrOp::Write || self.rd != 0
+    }
+}
+
+/// emulation of one or a range of CSRs. `read` returns None and `write` false for the
+/// accesses the zone is not allowed, they raise an illegal instruction
+struct CsrEmul {
+    first: usize,
+    last: usize,
+    read: fn(&ArchCpu, usize) -> Option<usize>,
+    write: fn(&mut ArchCpu, usize, usize) -> bool,
+}
+static CSR_EMUL_TABLE: &[CsrEmul] = &[
+    // the hypervisor keeps the entropy source for itself
+    CsrEmul {
+        first: CSR_SEED,
+        last: CSR_SEED,
+        read: csr_deny_read,
+        write: csr_deny_write,
+    },
+    // cycle and instret read as 0 unless the zone owns them
+    CsrEmul {
+        first: CSR_CYCLE,
+        last: CSR_CYCLE,
+        read: csr_counter_read,
+        write: csr_deny_write,
+    },
+    CsrEmul {
+        first: CSR_INSTRET,
+        last: CSR_INSTRET,
+        read: csr_counter_read,
+        write: csr_deny_write,
+    },
+    // hpmcounters of other zones read as 0, like unimplemented ones
+    CsrEmul {
+        first: CSR_HPMCOUNTER3,
+        last: CSR_HPMCOUNTER31,
+        read: csr_counter_read,
+        write: csr_deny_write,
+    },
+];
+fn csr_deny_read(_current_cpu: &ArchCpu, _csr: usize) -> Option<usize> {
+    None
+}
+fn csr_deny_write(_current_cpu: &mut ArchCpu, _csr: usize, _value: usize) -> bool {
+    false
+}
+fn csr_counter_read(current_cpu: &ArchCpu, csr: usize) -> Option<usize> {
+    Some(current_cpu.vpmu.read_hw_counter(csr).unwrap_or(0) as usize)
+}
+
+/// handle a VIRTUAL_INST exception of the guest
+pub fn virtual_inst_handler(current_cpu: &mut ArchCpu) {
+    // stval holds the trapping instruction, if the hardware reports it
+    let inst = match read_csr!(CSR_STVAL) as u32 {
+        0 => match read_inst(current_cpu, current_cpu.sepc) {
+            Ok(inst) => inst,
+            Err(cause) => {
+                inject_exception(current_cpu, cause, current_cpu.sepc);
+                return;
+            }
+        },
+        inst => inst,
+    };
+    if inst == INST_WFI && current_cpu.sstatus & SSTATUS_SPP != 0 {
+        wfi_handler(current_cpu);
+        return;
+    }
+    match CsrInst::decode(inst) {
+        Some(csr_inst) if csr_emulate(current_cpu, csr_inst) => current_cpu.sepc += 4,
+        _ => {
+            debug!(
+                "CPU {} illegal virtual instruction {:#x} at {:#x}",
+                current_cpu.hartid, inst, current_cpu.sepc
+            );
+            inject_exception(current_cpu, ExceptionType::ILLEGAL_INST, inst as usize);
+        }
+    }
+}
+/// guest wfi in VS mode: park the hart until an interrupt for the guest or the hypervisor
+/// is pending, there is no other vcpu to run on it meanwhile
+fn wfi_handler(current_cpu: &mut ArchCpu) {
+    current_cpu.sepc += 4;
+    // the pending virtual interrupts of hvip show up in hip, the wait sees them at once
+    if !current_cpu.wait_for_interrupt(SBI_HSM_STATE::STARTED) {
+        // the zone is shutting down or rebooting
+        current_cpu.stop();
+    }
+}
+/// emulate `inst` through the table, false if the guest gets an illegal instruction
+fn csr_emulate(current_cpu: &mut ArchCpu, inst: CsrInst) -> bool {
+    let Some(emul) = CSR_EMUL_TABLE
+        .iter()
+        .find(|emul| (emul.first..=emul.last).contains(&inst.csr))
+    else {
+        return false;
+    };
+    let src = if inst.imm {
+        inst.src
+    } else {
+        current_cpu.x[inst.src]
+    };
+    let old = if inst.reads() {
+        match (emul.read)(current_cpu, inst.csr) {
+            Some(old) => old,
+            None => return false,
+        }
+    } else {
+        0
+    };
+    if inst.writes() {
+        let value = match inst.op {
+            CsrOp::Write => src,
+            CsrOp::Set => old | src,
+            CsrOp::Clear => old & !src,
+        };
+        if !(emul.write)(current_cpu, inst.csr, value) {
+            return false;
+        }
+    }
+    trace!("CPU {} emulate {:x?}: {:#x}", current_cpu.hartid, inst, old);
+    if inst.rd != 0 {
+        current_cpu.x[inst.rd] = old;
+    }
+    true
+}
@@ -51,5 +51,65 @@ pub static GUEST2: [u8; include_bytes!("../../guests/img/Image-62U").len()] =
 // pub static GUEST2: [u8; include_bytes!("../../guests/os_ch5_802.bin").len()] =
 //     *include_bytes!("../../guests/os_ch5_802.bin");
 // pub static GUESTS: [(&'static [u8], &'static [u8]); 1] = [(&GUEST2, &GUEST2_DTB)];
-pub static GUESTS: [(&'static [u8], &'static [u8]); 2] =
-    [(&GUEST1, &GUEST1_DTB), (&GUEST2, &GUEST2_DTB)];
+/// static configuration of a zone
+pub struct ZoneConfig {
+    /// guest kernel image
+    pub image: &'static [u8],
+    /// guest device tree
+    pub dtb: &'static [u8],
+    /// mask of the SBI PMU counters owned by the zone on each of its harts
+    pub pmu_counters: usize,
+    /// interrupt sources owned by the zone besides those in its device tree
+    pub irqs: &'static [u32],
+    /// (guest irq, host irq) pairs for guest sources whose host source has another number,
+    /// added to the `hvisor,irq-map` of the guest plic node
+    pub irq_map: &'static [(u32, u32)],
+    /// interrupt sources raised by the hypervisor's emulated devices through `inject_virq`,
+    /// they are never routed to the host PLIC
+    pub virqs: &'static [u32],
+    /// physical hart backing each guest hart id of the device tree,
+    /// empty if the guest hart ids are the physical ones
+    pub harts: &'static [usize],
+    /// claims of one passthrough irq per 10ms window above which it is masked for a
+    /// while, 0 for no limit
+    pub irq_storm_limit: u32,
+    /// emulate the misaligned loads and stores of the guest, instead of raising the
+    /// misaligned exceptions in the guest for it to handle them
+    pub emulate_misaligned: bool,
+    /// trap the guest wfi and park the hart in the hypervisor until an interrupt for the
+    /// guest arrives, instead of letting the guest stall the hart in wfi
+    pub trap_wfi: bool,
+    /// back the guest RAM with frames allocated on the first access of each page, instead
+    /// of mapping it onto the host memory at the same address
+    pub demand_ram: bool,
+}
+pub static GUESTS: [ZoneConfig; 2] = [
+    ZoneConfig {
+        image: &GUEST1,
+        dtb: &GUEST1_DTB,
+        // cycle, instret, hpmcounter3~10
+        pmu_counters: 0x7fd,
+        irqs: &[],
+        irq_map: &[],
+        virqs: &[],
+        harts: &[0, 1, 2],
+        irq_storm_limit: 1000,
+        emulate_misaligned: true,
+        trap_wfi: true,
+        demand_ram: false,
+    },
+    ZoneConfig {
+        image: &GUEST2,
+        dtb: &GUEST2_DTB,
+        // cycle, instret, hpmcounter11~18
+        pmu_counters: 0x7f805,
+        irqs: &[],
+        irq_map: &[],
+        virqs: &[],
+        harts: &[],
+        irq_storm_limit: 1000,
+        emulate_misaligned: true,
+        trap_wfi: true,
+        demand_ram: false,
+    },
+];
@@ -1,9 +1,14 @@
 //! SBI console driver, for text output
 
-use crate::arch::riscv::sbi::console_putchar;
+use crate::arch::riscv::sbi::{console_getchar, console_putchar};
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 static PRINT_LOCK: Mutex<()> = Mutex::new(());
+/// no zone has an unterminated line on the host console
+const NO_OPEN_LINE: usize = usize::MAX;
+/// zone whose last output line is not terminated yet, written under PRINT_LOCK
+static OPEN_LINE: AtomicUsize = AtomicUsize::new(NO_OPEN_LINE);
 struct Stdout;
 
 impl Write for Stdout {
@@ -17,9 +22,17 @@ impl Write for Stdout {
 
 pub fn print(args: fmt::Arguments) {
     let _locked = PRINT_LOCK.lock();
+    close_open_line();
     Stdout.write_fmt(args).unwrap();
 }
 
+/// terminate the partial line of a zone before something else is printed, PRINT_LOCK held
+fn close_open_line() {
+    if OPEN_LINE.swap(NO_OPEN_LINE, Ordering::Relaxed) != NO_OPEN_LINE {
+        console_putchar(b'\n' as usize);
+    }
+}
+
 /// print string macro
 #[macro_export]
 macro_rules! print {
@@ -35,3 +48,116 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
 }
+
+/// size of the line buffer of a zone console
+const ZONE_CONSOLE_LINE_SIZE: usize = 256;
+/// zone receiving the characters typed on the host console
+static INPUT_FOCUS: AtomicUsize = AtomicUsize::new(0);
+/// typed on the host console, ctrl-a followed by a zone id digit moves the input focus to
+/// that zone, ctrl-a twice sends ctrl-a
+const INPUT_ESCAPE: u8 = 0x01;
+
+/// host console input shared by the zones
+struct ConsoleInput {
+    /// the last character read was the escape character
+    escape: bool,
+    /// character read by a zone without the focus, kept for the focused zone
+    pending: Option<u8>,
+}
+static CONSOLE_INPUT: Mutex<ConsoleInput> = Mutex::new(ConsoleInput {
+    escape: false,
+    pending: None,
+});
+
+/// set the zone that reads the host console input
+pub fn set_input_focus(vmid: usize) {
+    INPUT_FOCUS.store(vmid, Ordering::Release);
+    info!("console input focus on zone {}", vmid);
+}
+
+/// read the host console for zone `vmid`, any zone reading it handles the focus escape
+fn read_input(vmid: usize) -> Option<u8> {
+    let mut input = CONSOLE_INPUT.lock();
+    if input.pending.is_none() {
+        let c = match console_getchar() as isize {
+            -1 => return None,
+            c => c as u8,
+        };
+        if input.escape {
+            input.escape = false;
+            match c {
+                b'0'..=b'9' => set_input_focus((c - b'0') as usize),
+                INPUT_ESCAPE => input.pending = Some(c),
+                _ => {}
+            }
+        } else if c == INPUT_ESCAPE {
+            input.escape = true;
+        } else {
+            input.pending = Some(c);
+        }
+    }
+    if INPUT_FOCUS.load(Ordering::Acquire) != vmid {
+        return None;
+    }
+    input.pending.take()
+}
+
+/// virtual console of a zone, guest output is buffered during one SBI call and every line
+/// is tagged with the zone id
+pub struct ZoneConsole {
+    vmid: usize,
+    line: [u8; ZONE_CONSOLE_LINE_SIZE],
+    len: usize,
+}
+
+impl ZoneConsole {
+    pub const fn new(vmid: usize) -> Self {
+        Self {
+            vmid,
+            line: [0; ZONE_CONSOLE_LINE_SIZE],
+            len: 0,
+        }
+    }
+    /// print `buf`, the output is complete when the call returns
+    pub fn write(&mut self, buf: &[u8]) {
+        buf.iter().for_each(|&c| self.putchar(c));
+        self.flush();
+    }
+    pub fn putchar(&mut self, c: u8) {
+        if c != b'\r' {
+            self.line[self.len] = c;
+            self.len += 1;
+        }
+        if c == b'\n' || self.len == ZONE_CONSOLE_LINE_SIZE {
+            self.flush();
+        }
+    }
+    /// print the buffered output, a partial line stays open until the zone continues it
+    /// or something else is printed
+    pub fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let _locked = PRINT_LOCK.lock();
+        if OPEN_LINE.load(Ordering::Relaxed) != self.vmid {
+            close_open_line();
+            Stdout
+                .write_fmt(format_args!("[zone{}] ", self.vmid))
+                .unwrap();
+        }
+        self.line[..self.len]
+            .iter()
+            .for_each(|&c| console_putchar(c as usize));
+        let open = if self.line[self.len - 1] == b'\n' {
+            NO_OPEN_LINE
+        } else {
+            self.vmid
+        };
+        OPEN_LINE.store(open, Ordering::Relaxed);
+        self.len = 0;
+    }
+    /// read one character of the host console, only the zone holding the input focus gets it
+    pub fn getchar(&self) -> Option<u8> {
+        read_input(self.vmid)
+    }
+}
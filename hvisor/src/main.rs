@@ -19,11 +19,7 @@ use core::{arch::global_asm, mem};
 use fdt::Fdt;
 
 use crate::{
-    arch::riscv::{
-        cpu,
-        csr::*,
-        plic::{self, init_plic},
-    },
+    arch::riscv::{cpu, csr::*, irqchip},
     config::*,
     consts::{HV_PHY_BASE, MAX_CPU_NUM},
     error::HvResult,
@@ -48,6 +44,7 @@ mod consts;
 mod lang_items;
 mod logging;
 mod memory;
+mod mmio;
 mod percpu;
 mod zone;
 /// clear BSS segment
@@ -116,21 +113,15 @@ fn primary_init_early(dtb: usize) -> HvResult {
         println!("host cpu support sstc");
     }
     memory::init_hv_page_table(host_fdt).unwrap();
-    //mov to arch/  change to irq_chip
-    let plic_info = host_fdt.find_node("/soc/plic").unwrap();
-    init_plic(
-        plic_info.reg().unwrap().next().unwrap().starting_address as usize,
-        plic_info.reg().unwrap().next().unwrap().size.unwrap(),
-    );
+    irqchip::init_irqchip(&host_fdt);
     for vmid in 0..GUESTS.len() {
         info!(
             "guest{} addr: {:#x}, dtb addr: {:#x}",
             vmid,
-            GUESTS[vmid].0.as_ptr() as usize,
-            GUESTS[vmid].1.as_ptr() as usize
+            GUESTS[vmid].image.as_ptr() as usize,
+            GUESTS[vmid].dtb.as_ptr() as usize
         );
-        let vm_paddr_start: usize = GUESTS[vmid].0.as_ptr() as usize;
-        zone_create(vmid, vm_paddr_start, GUESTS[vmid].1.as_ptr(), DTB_ADDR);
+        zone_create(vmid, &GUESTS[vmid], DTB_ADDR).unwrap();
     }
     INIT_EARLY_OK.store(1, Ordering::Release);
     Ok(())
@@ -158,6 +158,26 @@ impl Frame {
         }
     }
 
+    /// Give up the ownership of the frames without freeing them, `from_raw` takes it back.
+    pub fn into_raw(self) -> PhysAddr {
+        let start_paddr = self.start_paddr;
+        core::mem::forget(self);
+        start_paddr
+    }
+
+    /// Take back the ownership of frames given up by `into_raw`, they are freed on drop.
+    ///
+    /// # Safety
+    ///
+    /// The frames must come from `into_raw` and must not be owned by another `Frame`.
+    pub unsafe fn from_raw(start_paddr: PhysAddr, frame_count: usize) -> Self {
+        assert!(is_aligned(start_paddr));
+        Self {
+            start_paddr,
+            frame_count,
+        }
+    }
+
     /// Get the start physical address of this frame.
     pub fn start_paddr(&self) -> PhysAddr {
         self.start_paddr
@@ -140,6 +140,11 @@ where
         self.pt.activate();
     }
 
+    /// Flush the TLB entries of `vaddr`, or all entries if None.
+    pub fn flush(&self, vaddr: Option<PT::VA>) {
+        self.pt.flush(vaddr)
+    }
+
     pub unsafe fn page_table_query(
         &self,
         vaddr: PT::VA,
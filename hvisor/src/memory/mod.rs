@@ -171,6 +171,21 @@ pub fn init_hv_page_table(fdt: fdt::Fdt) -> HvResult {
         }
     }
 
+    // probe aplic, only the S-level domain is programmed by the hypervisor
+    for node in fdt.find_all_nodes("/soc/aplic") {
+        if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
+            let paddr = reg.starting_address as HostPhysAddr;
+            let size = reg.size.unwrap();
+            debug!("map aplic addr: {:#x}, size: {:#x}", paddr, size);
+            hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+                paddr as GuestPhysAddr,
+                paddr,
+                size,
+                MemFlags::READ | MemFlags::WRITE,
+            ))?;
+        }
+    }
+
     for node in fdt.find_all_nodes("/soc/pci") {
         if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
             let paddr = reg.starting_address as HostPhysAddr;
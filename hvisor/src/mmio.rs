@@ -0,0 +1,82 @@
+//! MMIO emulation: devices registered per zone on guest physical address ranges that are
+//! left unmapped in stage 2, so that guest accesses fault into the hypervisor.
+use crate::arch::riscv::cpu::ArchCpu;
+use crate::error::HvResult;
+use crate::memory::GuestPhysAddr;
+use crate::zone::Zone;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// an emulated device, `offset` is relative to the start of its region and `width` is the
+/// access size in bytes; `current_cpu` is the vcpu doing the access and `zone` its zone,
+/// read-locked for the access
+pub trait MmioDevice: Send + Sync {
+    fn read(
+        &self,
+        current_cpu: &mut ArchCpu,
+        zone: &Zone,
+        offset: usize,
+        width: usize,
+    ) -> HvResult<usize>;
+    fn write(
+        &self,
+        current_cpu: &mut ArchCpu,
+        zone: &Zone,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> HvResult;
+}
+
+pub struct MmioRegion {
+    pub base: GuestPhysAddr,
+    pub size: usize,
+    pub dev: Arc<dyn MmioDevice>,
+}
+impl MmioRegion {
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        gpa >= self.base && gpa - self.base < self.size
+    }
+    fn overlaps(&self, base: GuestPhysAddr, size: usize) -> bool {
+        base < self.base + self.size && self.base < base + size
+    }
+}
+
+/// emulated MMIO regions of a zone
+pub struct MmioRegions {
+    regions: Vec<MmioRegion>,
+}
+impl MmioRegions {
+    pub const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+    pub fn register(
+        &mut self,
+        base: GuestPhysAddr,
+        size: usize,
+        dev: Arc<dyn MmioDevice>,
+    ) -> HvResult {
+        if self
+            .regions
+            .iter()
+            .any(|region| region.overlaps(base, size))
+        {
+            return hv_result_err!(
+                EEXIST,
+                format!("mmio region {:#x}~{:#x} overlaps", base, base + size)
+            );
+        }
+        info!("register mmio region {:#x}~{:#x}", base, base + size);
+        self.regions.push(MmioRegion { base, size, dev });
+        Ok(())
+    }
+    /// device backing `gpa` and the offset of `gpa` in its region
+    pub fn find(&self, gpa: GuestPhysAddr) -> Option<(Arc<dyn MmioDevice>, usize)> {
+        self.regions
+            .iter()
+            .find(|region| region.contains(gpa))
+            .map(|region| (region.dev.clone(), gpa - region.base))
+    }
+}
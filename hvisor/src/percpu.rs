@@ -1,10 +1,11 @@
 use core::mem;
 
 use crate::arch::riscv::cpu::ArchCpu;
+use crate::arch::riscv::sbi::SBI_HSM_STATE;
 use crate::consts::{INVALID_ADDRESS, PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
 use crate::memory::addr::VirtAddr;
 use crate::zone::Zone;
-use crate::{memory, read_csr, CSR_SIE, CSR_SIP};
+use crate::{memory, read_csr, CSR_SIE, CSR_SIP, CSR_SSCRARCH};
 use crate::{ACTIVATED_CPUS, ENTERED_CPUS};
 use alloc::sync::Arc;
 use core::sync::atomic::Ordering;
@@ -47,13 +48,22 @@ impl PerCpu {
         println!("prepare CPU{} for vm run!", self.id);
         if self.boot_cpu {
             println!("boot vm on CPU{}!", self.id);
+            self.arch_cpu
+                .hsm_state
+                .store(SBI_HSM_STATE::STARTED, Ordering::Release);
             self.arch_cpu.run();
         } else {
-            self.arch_cpu.idle();
+            // secondary harts stay stopped until the guest starts them by SBI HSM
+            self.arch_cpu.wait_for_start();
             self.arch_cpu.run();
         }
     }
 }
+/// id of the hart running this code, sscratch holds its vcpu while in the hypervisor
+pub fn this_cpu_id() -> usize {
+    let arch_cpu = read_csr!(CSR_SSCRARCH) as *const ArchCpu;
+    unsafe { (*arch_cpu).hartid }
+}
 pub fn get_cpu_data<'a>(cpu_id: usize) -> &'a mut PerCpu {
     let cpu_data: usize = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
     unsafe { &mut *(cpu_data as *mut PerCpu) }
@@ -1,19 +1,44 @@
+use crate::arch::riscv::aia::{
+    host_aia, map_guest_imsic, VirtAplic, VirtAplicDevice, APLIC_SIZE, IMSIC_VGEIN,
+};
+use crate::arch::riscv::plic::{host_plic, VirtPlic, VirtPlicDevice, PLIC_TOTAL_SIZE};
+use crate::arch::riscv::pmu::Vpmu;
 use crate::arch::riscv::s2pt::Stage2PageTable;
-use crate::consts::MAX_CPU_NUM;
+use crate::config::ZoneConfig;
+use crate::console::ZoneConsole;
+use crate::consts::{MAX_CPU_NUM, PAGE_SIZE};
 use crate::error::HvResult;
-use crate::memory::addr::align_up;
-use crate::memory::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, MemorySet};
+use crate::memory::addr::{align_up, page_count, page_offset, phys_to_virt};
+use crate::memory::{Frame, GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, MemorySet};
+use crate::mmio::MmioRegions;
 use crate::percpu::get_cpu_data;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::char::{decode_utf16, MAX};
 use core::mem::{self};
-use spin::RwLock;
+use core::sync::atomic::AtomicBool;
+use spin::{Mutex, RwLock};
+/// size of the huge pages backing the demand-allocated guest RAM
+const HUGE_PAGE_SIZE: usize = 0x20_0000;
 static ZONE_LIST: RwLock<Vec<Arc<RwLock<Zone>>>> = RwLock::new(vec![]);
 /// Add cell to ZONE_LIST
 pub fn add_zone(zone: Arc<RwLock<Zone>>) {
     ZONE_LIST.write().push(zone);
 }
+/// Remove zone from ZONE_LIST and detach it from its cpus
+pub fn remove_zone(vmid: usize) -> Option<Arc<RwLock<Zone>>> {
+    let mut zone_list = ZONE_LIST.write();
+    let index = zone_list.iter().position(|zone| zone.read().vmid == vmid)?;
+    let zone = zone_list.remove(index);
+    zone.read().cpu_set.iter().for_each(|cpuid| {
+        get_cpu_data(cpuid).zone = None;
+    });
+    Some(zone)
+}
+/// Number of zones still alive
+pub fn zone_count() -> usize {
+    ZONE_LIST.read().len()
+}
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct CpuSet {
@@ -63,40 +88,76 @@ pub struct Zone {
     pub vmid: usize,
     pub gpm: MemorySet<Stage2PageTable>,
     pub cpu_set: CpuSet,
+    /// original guest image, copied to the start of guest memory on every (re)boot
+    pub image: &'static [u8],
+    /// original guest device tree, copied to `dtb_frame` on every (re)boot
+    pub dtb: &'static [u8],
+    /// physical hart of each guest hart id, see `ZoneConfig::harts`
+    pub harts: &'static [usize],
+    pub dtb_frame: Frame,
+    pub dtb_addr: GuestPhysAddr,
+    pub entry: GuestPhysAddr,
+    pub mem_size: usize,
+    /// set while one hart is shutting down or rebooting the zone
+    pub resetting: AtomicBool,
+    /// virtual console used by the SBI console calls of the zone
+    pub console: Mutex<ZoneConsole>,
+    pub vplic: Mutex<VirtPlic>,
+    /// virtual APLIC domain, used instead of the vplic on AIA hosts
+    pub vaplic: Mutex<VirtAplic>,
+    /// emulated devices, reached through stage-2 faults on their unmapped ranges
+    pub mmio: MmioRegions,
+    /// the guest RAM is populated on demand by `populate_ram`, see `ZoneConfig`
+    pub demand_ram: bool,
 }
 impl Zone {
-    pub fn new(vmid: usize) -> Self {
-        Self {
+    pub fn new(vmid: usize, config: &'static ZoneConfig) -> HvResult<Self> {
+        Ok(Self {
             vmid,
             gpm: MemorySet::new(),
             cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
-        }
+            image: config.image,
+            dtb: config.dtb,
+            harts: config.harts,
+            dtb_frame: Frame::new_contiguous(page_count(config.dtb.len()), 0)?,
+            dtb_addr: 0,
+            entry: 0,
+            mem_size: 0,
+            resetting: AtomicBool::new(false),
+            console: Mutex::new(ZoneConsole::new(vmid)),
+            vplic: Mutex::new(VirtPlic::new(0, Vec::new(), Vec::new())),
+            vaplic: Mutex::new(VirtAplic::new(0)),
+            mmio: MmioRegions::new(),
+            demand_ram: config.demand_ram,
+        })
     }
-    pub fn pt_init(
-        &mut self,
-        vm_paddr_start: usize,
-        fdt: fdt::Fdt,
-        guest_dtb: usize,
-        dtb_addr: usize,
-    ) -> HvResult {
+    pub fn pt_init(&mut self, fdt: fdt::Fdt, dtb_addr: usize) -> HvResult {
         //debug!("fdt: {:?}", fdt);
-        // The first memory region is used to map the guest physical memory.
+        // The first memory region is used to map the guest physical memory,
+        // it is backed by the host memory at the same address.
         let mem_region = fdt.memory().regions().next().unwrap();
-        info!("map mem_region: {:?}", mem_region);
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            mem_region.starting_address as GuestPhysAddr,
-            vm_paddr_start as HostPhysAddr,
-            mem_region.size.unwrap(),
-            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
-        ))?;
+        if self.demand_ram {
+            info!("demand-allocated mem_region: {:?}", mem_region);
+        } else {
+            info!("map mem_region: {:?}", mem_region);
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                mem_region.starting_address as GuestPhysAddr,
+                mem_region.starting_address as HostPhysAddr,
+                mem_region.size.unwrap(),
+                MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+            ))?;
+        }
+        self.entry = mem_region.starting_address as GuestPhysAddr;
+        self.mem_size = mem_region.size.unwrap();
         // map guest dtb
         info!("map guest dtb: {:#x}", dtb_addr);
         self.gpm.insert(MemoryRegion::new_with_offset_mapper(
             dtb_addr as GuestPhysAddr,
-            guest_dtb as HostPhysAddr,
-            align_up(fdt.total_size()),
+            self.dtb_frame.start_paddr(),
+            self.dtb_frame.size(),
             MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
         ))?;
+        self.dtb_addr = dtb_addr;
         // probe virtio mmio device
         for node in fdt.find_all_nodes("/soc/virtio_mmio") {
             if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
@@ -191,35 +252,256 @@ impl Zone {
         info!("VM stage 2 memory set: {:#x?}", self.gpm);
         Ok(())
     }
+    /// translate a zone-local (virtual) hart id to the physical hart backing it
+    pub fn phart_of(&self, vhart: usize) -> Option<usize> {
+        let phart = if self.harts.is_empty() {
+            vhart
+        } else {
+            *self.harts.get(vhart)?
+        };
+        self.cpu_set.contains_cpu(phart).then_some(phart)
+    }
+    /// translate a physical hart of the zone to the hart id the guest knows it by
+    pub fn vhart_of(&self, phart: usize) -> Option<usize> {
+        if !self.cpu_set.contains_cpu(phart) {
+            None
+        } else if self.harts.is_empty() {
+            Some(phart)
+        } else {
+            self.harts.iter().position(|&p| p == phart)
+        }
+    }
+    /// translate `gpa` through the stage-2 mapping, checking the access is allowed; demand
+    /// RAM the guest has not touched yet is populated first
+    pub fn guest_to_host(
+        &mut self,
+        gpa: GuestPhysAddr,
+        access: MemFlags,
+    ) -> HvResult<HostPhysAddr> {
+        if self.demand_ram && self.is_ram(gpa) {
+            self.populate_ram(gpa)?;
+        }
+        let (hpa, flags, _) = unsafe { self.gpm.page_table_query(gpa) }?;
+        if !flags.contains(access) {
+            return hv_result_err!(EFAULT, format!("guest address {:#x} not accessible", gpa));
+        }
+        Ok(hpa)
+    }
+    /// copy guest memory at `gpa` into `buf`, the range may span several pages
+    pub fn read_guest(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HvResult {
+        let mut done = 0;
+        while done < buf.len() {
+            let hpa = self.guest_to_host(gpa + done, MemFlags::READ)?;
+            let len = (PAGE_SIZE - page_offset(gpa + done)).min(buf.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    phys_to_virt(hpa) as *const u8,
+                    buf[done..].as_mut_ptr(),
+                    len,
+                );
+            }
+            done += len;
+        }
+        Ok(())
+    }
+    /// copy `buf` into guest memory at `gpa`, the range may span several pages
+    pub fn write_guest(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> HvResult {
+        let mut done = 0;
+        while done < buf.len() {
+            let hpa = self.guest_to_host(gpa + done, MemFlags::WRITE)?;
+            let len = (PAGE_SIZE - page_offset(gpa + done)).min(buf.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buf[done..].as_ptr(),
+                    phys_to_virt(hpa) as *mut u8,
+                    len,
+                );
+            }
+            done += len;
+        }
+        Ok(())
+    }
+    /// copy the original image and device tree into the zone, used on creation and reboot
+    pub fn load(&mut self) -> HvResult {
+        if self.image.len() > self.mem_size {
+            return hv_result_err!(E2BIG, format!("zone {} image too large", self.vmid));
+        }
+        info!(
+            "zone {} load image {:#x} bytes at {:#x}",
+            self.vmid,
+            self.image.len(),
+            self.entry
+        );
+        self.write_guest(self.entry, self.image)?;
+        self.dtb_frame.zero();
+        self.dtb_frame.copy_data_from(self.dtb);
+        Ok(())
+    }
+    /// true if `gpa` is in the guest RAM
+    pub fn is_ram(&self, gpa: GuestPhysAddr) -> bool {
+        gpa >= self.entry && gpa - self.entry < self.mem_size
+    }
+    /// back the demand-allocated guest RAM page of `gpa` with zeroed frames, the whole
+    /// 2M page around it when it is not populated yet and the frames are available
+    pub fn populate_ram(&mut self, gpa: GuestPhysAddr) -> HvResult {
+        if unsafe { self.gpm.page_table_query(gpa) }.is_ok() {
+            // populated by another hart meanwhile
+            return Ok(());
+        }
+        let huge_base = gpa & !(HUGE_PAGE_SIZE - 1);
+        let huge = self.is_ram(huge_base)
+            && self.is_ram(huge_base + HUGE_PAGE_SIZE - 1)
+            && (huge_base..huge_base + HUGE_PAGE_SIZE)
+                .step_by(PAGE_SIZE)
+                .all(|gpa| unsafe { self.gpm.page_table_query(gpa) }.is_err());
+        // the page table maps it with a 2M page if the frames are 2M aligned too
+        let (start, mut frame) = match huge
+            .then(|| Frame::new_contiguous(HUGE_PAGE_SIZE / PAGE_SIZE, 9).ok())
+            .flatten()
+        {
+            Some(frame) => (huge_base, frame),
+            None => (gpa & !(PAGE_SIZE - 1), Frame::new()?),
+        };
+        frame.zero();
+        trace!(
+            "zone {} populate {:#x}~{:#x} -> {:#x}",
+            self.vmid,
+            start,
+            start + frame.size(),
+            frame.start_paddr()
+        );
+        self.gpm.map_partial(&MemoryRegion::new_with_offset_mapper(
+            start,
+            frame.start_paddr(),
+            frame.size(),
+            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+        ))?;
+        self.gpm.flush(Some(start));
+        // freed by `release_ram` when the zone is dropped
+        frame.into_raw();
+        Ok(())
+    }
+    /// free the frames populated in the guest RAM
+    fn release_ram(&mut self) {
+        let mut gpa = self.entry;
+        while self.is_ram(gpa) {
+            match unsafe { self.gpm.page_table_query(gpa) } {
+                Ok((hpa, _, size)) => {
+                    drop(unsafe { Frame::from_raw(hpa, size as usize / PAGE_SIZE) });
+                    gpa += size as usize;
+                }
+                Err(_) => gpa += PAGE_SIZE,
+            }
+        }
+    }
+    /// host interrupt sources the zone owns, virtual sources excluded
+    pub fn host_irqs(&self) -> Vec<usize> {
+        if host_aia().is_some() {
+            return self.vaplic.lock().owned_irqs().collect();
+        }
+        self.vplic.lock().host_irqs().collect()
+    }
+    /// give back the host plic contexts of the zone's cpus: complete the irqs still claimed
+    /// by the guest and disable all sources
+    pub fn release_irqs(&self) {
+        if let Some(aia) = host_aia() {
+            // pending MSIs stay in the guest files, the guest clears them on boot
+            self.vaplic.lock().reset(&aia.aplic);
+            return;
+        }
+        // same lock order as the vplic emulation
+        let mut vplic = self.vplic.lock();
+        let host_plic = host_plic();
+        vplic
+            .host_irqs()
+            .for_each(|irq| host_plic.set_priority(irq, 0));
+        vplic.reset();
+        self.cpu_set.iter().for_each(|cpuid| {
+            let arch_cpu = &get_cpu_data(cpuid).arch_cpu;
+            arch_cpu.irq_pending.clear();
+            host_plic.reset_context(host_plic.smode_context(cpuid), &arch_cpu.irq_in_service);
+        });
+    }
     pub fn gpm_activate(&self) {
         unsafe { self.gpm.activate() }
     }
 }
+/// guest address of the first reg of node `path`
+fn fdt_reg_base(fdt: &fdt::Fdt, path: &str) -> Option<GuestPhysAddr> {
+    let reg = fdt.find_node(path)?.reg()?.next()?;
+    Some(reg.starting_address as GuestPhysAddr)
+}
+impl Drop for Zone {
+    fn drop(&mut self) {
+        if self.demand_ram {
+            self.release_ram();
+        }
+    }
+}
 pub fn zone_create(
     vmid: usize,
-    vm_paddr_start: usize,
-    dtb_ptr: *const u8,
+    config: &'static ZoneConfig,
     dtb_addr: usize,
 ) -> HvResult<Arc<RwLock<Zone>>> {
     // we create the new zone here
     //TODO: create Zone with cpu_set
-    let guest_fdt = unsafe { fdt::Fdt::from_ptr(dtb_ptr) }.unwrap();
+    let guest_fdt = unsafe { fdt::Fdt::from_ptr(config.dtb.as_ptr()) }.unwrap();
     let guest_entry = guest_fdt
         .memory()
         .regions()
         .next()
         .unwrap()
         .starting_address as usize;
-    let mut zone = Zone::new(vmid);
-    zone.pt_init(vm_paddr_start, guest_fdt, dtb_ptr as usize, dtb_addr)
-        .unwrap();
+    let mut zone = Zone::new(vmid, config)?;
+    zone.pt_init(guest_fdt, dtb_addr).unwrap();
+    zone.load()?;
     guest_fdt.cpus().for_each(|cpu| {
-        let cpu_id = cpu.ids().all().next().unwrap();
-        zone.cpu_set.set_bit(cpu_id as usize);
+        let vhart = cpu.ids().all().next().unwrap();
+        let phart = if config.harts.is_empty() {
+            vhart
+        } else {
+            config.harts[vhart]
+        };
+        zone.cpu_set.set_bit(phart);
     });
     //TODO:assign cpu according to cpu_set
     //TODO:set cpu entry
     info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
+    if host_aia().is_some() {
+        zone.vaplic = Mutex::new(VirtAplic::from_fdt(&guest_fdt, config.irqs));
+        map_guest_imsic(&mut zone, &guest_fdt)?;
+        if let Some(base) = fdt_reg_base(&guest_fdt, "/soc/aplic") {
+            zone.mmio
+                .register(base, APLIC_SIZE, Arc::new(VirtAplicDevice))?;
+        }
+    } else {
+        let mut vplic = VirtPlic::from_fdt(&guest_fdt, config.irqs, config.irq_map, |vhart| {
+            zone.phart_of(vhart)
+        });
+        config
+            .virqs
+            .iter()
+            .for_each(|&virq| vplic.add_virtual_source(virq));
+        zone.vplic = Mutex::new(vplic);
+        if let Some(base) = fdt_reg_base(&guest_fdt, "/soc/plic") {
+            zone.mmio
+                .register(base, PLIC_TOTAL_SIZE, Arc::new(VirtPlicDevice))?;
+        }
+    }
+    // a host source is owned by one zone only
+    let irqs = zone.host_irqs();
+    for other in ZONE_LIST.read().iter() {
+        let other = other.read();
+        if let Some(irq) = other.host_irqs().into_iter().find(|irq| irqs.contains(irq)) {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "zone {} irq {} already owned by zone {}",
+                    vmid, irq, other.vmid
+                )
+            );
+        }
+    }
     let cpu_set = zone.cpu_set;
 
     let new_zone_pointer = Arc::new(RwLock::new(zone));
@@ -234,9 +516,18 @@ pub fn zone_create(
             info!("set cpu{} first_cpu{}", cpuid, cpu_set.first_cpu().unwrap());
             cpu_data.arch_cpu.first_cpu = cpu_set.first_cpu().unwrap();
             cpu_data.cpu_on_entry = guest_entry;
+            let vhart = new_zone_pointer.read().vhart_of(cpuid).unwrap();
+            cpu_data.arch_cpu.vhartid = vhart;
+            cpu_data.arch_cpu.vpmu = Vpmu::new(config.pmu_counters);
+            cpu_data.arch_cpu.irq_storm_limit = config.irq_storm_limit;
+            cpu_data.arch_cpu.emulate_misaligned = config.emulate_misaligned;
+            cpu_data.arch_cpu.trap_wfi = config.trap_wfi;
+            if host_aia().is_some() {
+                cpu_data.arch_cpu.vgein = IMSIC_VGEIN;
+            }
             let cpu_isa = guest_fdt
                 .cpus()
-                .find(|cpu| cpu.ids().all().next().unwrap() == cpuid)
+                .find(|cpu| cpu.ids().all().next().unwrap() == vhart)
                 .unwrap()
                 .properties()
                 .find(|p| p.name == "riscv,isa")